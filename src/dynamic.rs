@@ -0,0 +1,346 @@
+use anyhow::anyhow;
+use anyhow::Result;
+use std::collections::HashMap;
+
+use crate::exec::{self, Plan};
+use crate::farm;
+use crate::joblist::{JobEntry, JobList, OnFailure};
+use crate::launch::{Launcher, Step, Supervisor};
+use crate::map::ProcMap;
+use crate::status::TaskStatus;
+
+/// Jobs connected to the given one by colocate_with or avoid, itself included,
+/// they have to be mapped together
+fn partners<'a>(jobs: &'a [JobEntry], job: &'a JobEntry) -> Vec<&'a JobEntry> {
+    let mut ret = vec![job];
+    let mut i = 0;
+
+    while i < ret.len() {
+        let cur = ret[i];
+        for j in jobs.iter() {
+            let linked = [cur.colocate_with.as_ref(), cur.avoid.as_ref()]
+                .into_iter()
+                .flatten()
+                .any(|n| j.name.as_ref() == Some(n))
+                || [j.colocate_with.as_ref(), j.avoid.as_ref()]
+                    .into_iter()
+                    .flatten()
+                    .any(|n| cur.name.as_ref() == Some(n));
+
+            if linked && !ret.iter().any(|r| r.id == j.id) {
+                ret.push(j);
+            }
+        }
+        i += 1;
+    }
+
+    ret
+}
+
+/// Launch each job in its own step as soon as the jobs it comes after ended
+/// and enough slots are free, mapping it over the slots left by running jobs.
+/// While other jobs still run, slots freed by a job are filled with new
+/// instances of the elastic jobs, until an instance fails or a farm has no
+/// task left
+pub(crate) fn run(
+    launcher: &Launcher,
+    display: bool,
+    mut pmap: ProcMap,
+    stages: Vec<JobList>,
+) -> Result<()> {
+    let status_dir = TaskStatus::reset_dir(&launcher.dir)?;
+
+    let mut queued: Vec<JobEntry> = stages.into_iter().flat_map(|s| s.into_jobs()).collect();
+    /* Elastic jobs still grown, and the elastic job of each instance */
+    let mut elastic: Vec<JobEntry> = queued.iter().filter(|j| j.elastic).cloned().collect();
+    let mut origins: HashMap<u32, u32> = HashMap::new();
+    let mut next_id = queued.iter().map(|j| j.id + 1).max().unwrap_or(0);
+    let mut instances = 0;
+
+    let mut supervisor =
+        Supervisor::new(launcher, JobList::from_jobs(queued.clone()).grace_period());
+    /* Every rank launched so far, for the final report */
+    let mut plan = Plan::default();
+    /* Running jobs by label with their id and the elastic job they grow */
+    let mut running: Vec<(String, u32, Option<u32>)> = Vec::new();
+    /* Names of the jobs which ended and let the jobs after them run */
+    let mut completed: Vec<String> = Vec::new();
+    /* Labels of the jobs which failed or will never run */
+    let mut failed: Vec<String> = Vec::new();
+    let mut never: Vec<JobEntry> = Vec::new();
+    let mut freed = false;
+    let mut batch = 0;
+
+    loop {
+        if !supervisor.stopping() {
+            let deps_done = |j: &JobEntry| j.after.iter().all(|a| completed.contains(a));
+
+            let ready: Vec<u32> = queued
+                .iter()
+                .filter(|j| partners(&queued, j).iter().all(|p| deps_done(p)))
+                .map(|j| j.id)
+                .collect();
+
+            let mut candidates: Vec<(Vec<JobEntry>, bool)> = Vec::new();
+            /* Queued jobs are tried first, they can all still run */
+
+            if !ready.is_empty() {
+                candidates.push((
+                    queued
+                        .iter()
+                        .filter(|j| ready.contains(&j.id))
+                        .cloned()
+                        .collect(),
+                    false,
+                ));
+            }
+
+            /* Elastic jobs only fill gaps left while other jobs run */
+            let others = running.iter().any(|(_, _, e)| e.is_none()) || !queued.is_empty();
+
+            /* Instances of a farm without unclaimed tasks would end right away */
+            if freed && others {
+                let mut done = Vec::new();
+                for j in elastic.iter().filter(|j| j.farm.is_some()) {
+                    if !farm::pending(j)? {
+                        println!("[dynamic] {} has no task left, not growing it", j.label());
+                        done.push(j.id);
+                    }
+                }
+                elastic.retain(|j| !done.contains(&j.id));
+            }
+
+            if freed && others && !elastic.is_empty() {
+                let grown = elastic
+                    .iter()
+                    .map(|j| {
+                        origins.insert(next_id, j.id);
+                        let mut j = j.clone();
+                        j.name = Some(format!("{}.{}", j.label(), instances + 1));
+                        j.id = next_id;
+                        j.after.clear();
+                        j.colocate_with = None;
+                        j.avoid = None;
+                        next_id += 1;
+                        j
+                    })
+                    .collect();
+                candidates.push((grown, true));
+            }
+            freed = false;
+            let mut waiting = false;
+
+            for (jobs, grown) in candidates {
+                /* Slots go to ready jobs which do not fit yet rather than to
+                new elastic instances which would hold them */
+                if grown && waiting {
+                    continue;
+                }
+
+                let mut list = JobList::from_jobs(jobs);
+                let labels: Vec<String> = list.iter().map(|j| j.label()).collect();
+
+                if let Err(e) = pmap.map(&mut list) {
+                    if running.is_empty() && !grown {
+                        return Err(e);
+                    }
+                    waiting |= !grown;
+                    println!(
+                        "[dynamic] {} cannot be mapped on {} free slot(s) yet",
+                        labels.join(", "),
+                        pmap.count_free_slots()
+                    );
+                    continue;
+                }
+
+                queued.retain(|j| !list.iter().any(|l| l.id == j.id));
+                if grown {
+                    instances += 1;
+                }
+
+                if display {
+                    pmap.display(Some(&list));
+                }
+
+                batch += 1;
+                let file = launcher.dir.join(format!("mapping-{}.json", batch));
+                let mapping = pmap.to_plan(&list);
+                mapping.save(file.clone())?;
+                let wrapper = exec::wrapper_args(&file, &status_dir)?;
+
+                for j in list.iter() {
+                    let label = j.label();
+                    let ranks = mapping.ranks.iter().filter(|r| r.job == label).count();
+                    println!(
+                        "[dynamic] launching {} on {} rank(s), {} slot(s) left free",
+                        label,
+                        ranks,
+                        pmap.count_free_slots()
+                    );
+                    supervisor.add(Step::job(launcher, &mapping, &label, &wrapper)?)?;
+                    let grows = j
+                        .elastic
+                        .then(|| origins.get(&j.id).copied().unwrap_or(j.id));
+                    running.push((label, j.id, grows));
+                }
+
+                plan.ranks.extend(mapping.ranks);
+            }
+        }
+
+        let (job, status) = match supervisor.next_ended()? {
+            Some((Some(job), status)) => (job, status),
+            Some((None, _)) => continue,
+            None => break,
+        };
+
+        if let Some(pos) = running.iter().position(|(l, _, _)| *l == job) {
+            let (_, id, grows) = running.remove(pos);
+            let count = pmap.release(id);
+            freed = count != 0;
+            println!(
+                "[dynamic] {} ended ({}), {} slot(s) freed",
+                job,
+                TaskStatus::from_exit(-1, status).reason,
+                count
+            );
+
+            /* Another instance would most likely fail the same way */
+            if let Some(origin) = grows.filter(|_| !status.success()) {
+                if let Some(pos) = elastic.iter().position(|j| j.id == origin) {
+                    println!(
+                        "[dynamic] {} failed, not growing {} anymore",
+                        job,
+                        elastic[pos].label()
+                    );
+                    elastic.remove(pos);
+                }
+            }
+        }
+
+        let ignores = plan
+            .ranks
+            .iter()
+            .any(|r| r.job == job && r.on_failure == Some(OnFailure::Ignore));
+        if status.success() || ignores {
+            completed.push(job);
+            continue;
+        }
+        failed.push(job);
+
+        /* Jobs after a failed job, or after such jobs, can never run */
+        loop {
+            let doomed: Vec<u32> = queued
+                .iter()
+                .filter(|j| {
+                    partners(&queued, j)
+                        .iter()
+                        .any(|p| p.after.iter().any(|a| failed.contains(a)))
+                })
+                .map(|j| j.id)
+                .collect();
+
+            if doomed.is_empty() {
+                break;
+            }
+
+            for j in queued.iter().filter(|j| doomed.contains(&j.id)) {
+                failed.push(j.label());
+                never.push(j.clone());
+            }
+            queued.retain(|j| !doomed.contains(&j.id));
+        }
+    }
+
+    /* Jobs still queued when lmap was stopped */
+    never.append(&mut queued);
+
+    if !never.is_empty() {
+        eprintln!(
+            "lmap: {} never ran, the jobs they come after failed or were stopped",
+            never
+                .iter()
+                .map(|j| j.label())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    supervisor.finish()?.report(&plan, &status_dir, true)?;
+
+    if !never.is_empty() {
+        return Err(anyhow!("{} job(s) never ran", never.len()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::launch::tests::SIGNALS;
+    use crate::map::JobDesc;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Duration;
+
+    #[test]
+    fn failed_elastic_instances_are_not_grown_again() {
+        let _lock = SIGNALS.lock().unwrap_or_else(|e| e.into_inner());
+
+        /* Steps log their job, fill fails right away while main runs */
+        let dir = std::env::temp_dir().join(format!("lmap-dynamic-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let srun = dir.join("srun");
+        std::fs::write(
+            &srun,
+            format!(
+                "#!/bin/sh\n\
+                 for a; do job=$a; done\n\
+                 echo $job >> {}\n\
+                 case $job in fill*) exit 1;; esac\n\
+                 sleep 1\n",
+                dir.join("launched").display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&srun, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let launcher = Launcher {
+            program: srun.display().to_string(),
+            margin: Duration::from_millis(200),
+            dir: dir.clone(),
+        };
+
+        let descs: Vec<JobDesc> = (0..4)
+            .map(|pu| JobDesc {
+                host: "n0".to_string(),
+                rank: pu as u32,
+                numa: vec![0],
+                pu: vec![vec![pu]],
+                core: vec![vec![pu]],
+                features: Default::default(),
+            })
+            .collect();
+        let jobs = JobList::parse(
+            "- name: main\n  map: 2slot\n  command: [a]\n\
+             - name: fill\n  map: Aslot\n  command: [b]\n  elastic: true\n",
+        )
+        .unwrap();
+
+        let ret = run(
+            &launcher,
+            false,
+            ProcMap::from_descs(&descs),
+            jobs.into_stages().unwrap(),
+        );
+        /* Nothing saved the status of the ranks */
+        assert!(ret.is_err());
+
+        let launched = std::fs::read_to_string(dir.join("launched")).unwrap();
+        let mut launched: Vec<&str> = launched.lines().collect();
+        launched.sort();
+        assert_eq!(launched, ["fill", "main"]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
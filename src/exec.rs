@@ -0,0 +1,509 @@
+use anyhow::anyhow;
+use anyhow::Result;
+use hwlocality::cpu::binding::CpuBindingFlags;
+use hwlocality::cpu::cpuset::CpuSet;
+use hwlocality::memory::binding::{MemoryBindingFlags, MemoryBindingPolicy};
+use hwlocality::memory::nodeset::NodeSet;
+use hwlocality::Topology;
+use serde::Deserialize;
+use serde::Serialize;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use std::time::{Duration, Instant};
+
+use crate::joblist::{format_duration, OnFailure};
+use crate::launch::{catch_signals, send_signal, take_signal};
+use crate::status::TaskStatus;
+
+/// Time a command gets to exit after its stop signal when its job sets none
+pub(crate) const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// What a launcher task has to run, as computed by the mapper
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct RankPlan {
+    /// Rank of the task in the launcher
+    pub(crate) task: i32,
+    pub(crate) host: String,
+    /// Label of the job running on the task
+    pub(crate) job: String,
+    /// Rank of the task in its job
+    pub(crate) rank: usize,
+    /// Number of ranks of the job
+    pub(crate) size: usize,
+    pub(crate) pus: Vec<usize>,
+    pub(crate) numas: Vec<usize>,
+    /// Extra environment as KEY=VALUE
+    pub(crate) env: Vec<String>,
+    pub(crate) command: Vec<String>,
+    /// Output file template, see RankPlan::output_file
+    pub(crate) output: Option<String>,
+    #[serde(default)]
+    pub(crate) on_failure: Option<OnFailure>,
+    /// Signal sent to the command when the task is asked to stop
+    #[serde(default)]
+    pub(crate) stop_signal: Option<i32>,
+    #[serde(default)]
+    pub(crate) grace_period: Option<Duration>,
+    /// Time after which the command is stopped
+    #[serde(default)]
+    pub(crate) timeout: Option<Duration>,
+}
+
+impl RankPlan {
+    /// Expand {job}, {rank}, {task} and {host} in the output template
+    fn output_file(&self) -> Option<PathBuf> {
+        self.output.as_ref().map(|t| {
+            PathBuf::from(
+                t.replace("{job}", &self.job)
+                    .replace("{rank}", &self.rank.to_string())
+                    .replace("{task}", &self.task.to_string())
+                    .replace("{host}", &self.host),
+            )
+        })
+    }
+}
+
+/// Ranks of every job of a mapping, written next to the jobfile
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub(crate) struct Plan {
+    pub(crate) ranks: Vec<RankPlan>,
+}
+
+impl Plan {
+    pub(crate) fn save(&self, out: PathBuf) -> Result<()> {
+        let file = std::fs::File::create(out)?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), self)?;
+        Ok(())
+    }
+
+    pub(crate) fn load(file: PathBuf) -> Result<Plan> {
+        let data = std::fs::read_to_string(&file)
+            .map_err(|e| anyhow!("Failed to read plan {} : {}", file.display(), e))?;
+        Ok(serde_json::from_str(&data)?)
+    }
+
+    pub(crate) fn rank(&self, task: i32) -> Option<&RankPlan> {
+        self.ranks.iter().find(|r| r.task == task)
+    }
+
+    /// Rank of a job launched in its own step
+    pub(crate) fn job_rank(&self, job: &str, rank: usize) -> Option<&RankPlan> {
+        self.ranks.iter().find(|r| r.job == job && r.rank == rank)
+    }
+
+    /// Labels of the jobs in plan order
+    pub(crate) fn jobs(&self) -> Vec<&str> {
+        let mut ret: Vec<&str> = Vec::new();
+        for r in self.ranks.iter() {
+            if !ret.contains(&r.job.as_str()) {
+                ret.push(&r.job);
+            }
+        }
+        ret
+    }
+}
+
+/// Rank of the current process as given by the launcher
+pub(crate) fn launcher_rank() -> Option<i32> {
+    [
+        "PMI_RANK",
+        "PMIX_RANK",
+        "SLURM_PROCID",
+        "OMPI_COMM_WORLD_RANK",
+    ]
+    .iter()
+    .find_map(|v| std::env::var(v).ok())
+    .and_then(|v| v.parse().ok())
+}
+
+fn bind(rank: &RankPlan) -> Result<()> {
+    let topology = Topology::new()?;
+
+    let mut cpus = CpuSet::new();
+    for pu in rank.pus.iter() {
+        cpus.set(*pu);
+    }
+
+    topology
+        .bind_cpu(&cpus, CpuBindingFlags::PROCESS)
+        .map_err(|e| {
+            anyhow!(
+                "Failed to bind task {} to PUs {:?} : {}",
+                rank.task,
+                rank.pus,
+                e
+            )
+        })?;
+
+    let mut numas = NodeSet::new();
+    for n in rank.numas.iter() {
+        numas.set(*n);
+    }
+
+    /* Memory binding is not supported everywhere, running unbound is fine */
+    if let Err(e) = topology.bind_memory(
+        &numas,
+        MemoryBindingPolicy::Bind,
+        MemoryBindingFlags::PROCESS,
+    ) {
+        eprintln!(
+            "lmap: failed to bind memory of task {} to NUMA {:?} : {}",
+            rank.task, rank.numas, e
+        );
+    }
+
+    Ok(())
+}
+
+/// Why the wrapper stopped the command
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stop {
+    /// SIGINT or SIGTERM reached the task
+    Requested,
+    /// The command ran for longer than the timeout of its job
+    Timeout,
+}
+
+/// Run the command as a child in its own process group so that it only gets
+/// the signals the wrapper forwards. SIGINT, SIGTERM or the timeout of the job
+/// stop the task: the command gets the stop signal of its job and is killed
+/// once the grace period is over. Tells why the task was stopped, if it was
+fn supervise(cmd: &mut Command, rank: &RankPlan) -> std::io::Result<(ExitStatus, Option<Stop>)> {
+    let mut child = cmd.process_group(0).spawn()?;
+    let group = -(child.id() as i32);
+    let timeout = rank.timeout.map(|t| Instant::now() + t);
+    let mut stop: Option<Stop> = None;
+    let mut deadline: Option<Instant> = None;
+
+    loop {
+        if let Some(st) = child.try_wait()? {
+            return Ok((st, stop));
+        }
+
+        let reason = match take_signal() {
+            Some(libc::SIGINT) | Some(libc::SIGTERM) => Some(Stop::Requested),
+            Some(sig) => {
+                let _ = send_signal(group, sig);
+                None
+            }
+            None if timeout.is_some_and(|t| Instant::now() >= t) => Some(Stop::Timeout),
+            None => None,
+        };
+
+        if let Some(r) = reason.filter(|_| stop.is_none()) {
+            let sig = rank.stop_signal.unwrap_or(libc::SIGTERM);
+            /* The command may already be gone */
+            let _ = send_signal(group, sig);
+            stop = Some(r);
+            deadline = Some(Instant::now() + rank.grace_period.unwrap_or(DEFAULT_GRACE_PERIOD));
+        }
+
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            let _ = send_signal(group, libc::SIGKILL);
+        }
+
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Run the command until it succeeds, is stopped or used up its restarts,
+/// gives the status of the last attempt
+fn attempts(cmd: &mut Command, rank: &RankPlan, restarts: usize) -> std::io::Result<TaskStatus> {
+    let task = rank.task;
+    let mut attempt = 0;
+
+    loop {
+        let (st, stop) = supervise(cmd, rank)?;
+        let mut st = TaskStatus {
+            job: rank.job.clone(),
+            ..TaskStatus::from_exit(task, st)
+        };
+
+        if stop == Some(Stop::Timeout) {
+            let limit = rank.timeout.map(format_duration).unwrap_or_default();
+            eprintln!(
+                "lmap: {} rank {} (task {}) timed out after {}",
+                rank.job, rank.rank, task, limit
+            );
+            st.reason = format!("timed out after {} ({})", limit, st.reason);
+            st.timed_out = true;
+            /* Like timeout(1) */
+            st.code = Some(124);
+        }
+
+        /* A task stopped or timed out is not restarted */
+        if !st.failed() || stop.is_some() || attempt == restarts {
+            if attempt != 0 {
+                st.reason = format!("{} after {} restart(s)", st.reason, attempt);
+            }
+            return Ok(st);
+        }
+
+        attempt += 1;
+        eprintln!(
+            "lmap: {} rank {} (task {}) failed with {}, restart {}/{}",
+            rank.job, rank.rank, task, st.reason, attempt, restarts
+        );
+    }
+}
+
+/// Command line of the exec wrapper for the given plan
+pub(crate) fn wrapper_args(plan: &Path, status_dir: &Path) -> Result<Vec<String>> {
+    Ok(vec![
+        std::env::current_exe()?.display().to_string(),
+        "exec".to_string(),
+        "--plan".to_string(),
+        plan.display().to_string(),
+        "--status".to_string(),
+        status_dir.display().to_string(),
+    ])
+}
+
+/// Bind the current process as the plan says and replace it with the command,
+/// the command of the job is used if none is given. With a status directory,
+/// a restart or a stop policy the command runs as a child, its exit status is
+/// saved in the directory. When the job is given the task was launched in a
+/// step of its own and the launcher rank is its rank in the job
+pub(crate) fn run(
+    plan: PathBuf,
+    status: Option<PathBuf>,
+    job: Option<String>,
+    command: Vec<String>,
+) -> Result<()> {
+    let plan = Plan::load(plan)?;
+
+    let launcher = launcher_rank().ok_or(anyhow!(
+        "Cannot find the rank of this task (PMI_RANK, PMIX_RANK, SLURM_PROCID or OMPI_COMM_WORLD_RANK)"
+    ))?;
+
+    let rank = match job.as_deref() {
+        Some(j) => usize::try_from(launcher)
+            .ok()
+            .and_then(|r| plan.job_rank(j, r))
+            .ok_or(anyhow!("Job {} has no rank {} in the plan", j, launcher))?,
+        /* Tasks without a rank in the plan are idle */
+        None => match plan.rank(launcher) {
+            Some(r) => r,
+            None => return Ok(()),
+        },
+    };
+
+    /* Statuses are saved under the task of the rank in the plan */
+    let task = rank.task;
+
+    let command = if command.is_empty() {
+        &rank.command
+    } else {
+        &command
+    };
+
+    let (exe, args) = command
+        .split_first()
+        .ok_or(anyhow!("No command to run for task {}", task))?;
+
+    bind(rank)?;
+
+    let mut cmd = Command::new(exe);
+    cmd.args(args);
+
+    for var in rank.env.iter() {
+        if let Some((k, v)) = var.split_once('=') {
+            cmd.env(k, v);
+        }
+    }
+
+    if let Some(out) = rank.output_file() {
+        if let Some(dir) = out.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = std::fs::File::create(&out)
+            .map_err(|e| anyhow!("Failed to create output {} : {}", out.display(), e))?;
+        cmd.stderr(file.try_clone()?);
+        cmd.stdout(file);
+    }
+
+    let restarts = match rank.on_failure {
+        Some(OnFailure::Restart(n)) => n,
+        _ => 0,
+    };
+
+    let stoppable =
+        rank.stop_signal.is_some() || rank.grace_period.is_some() || rank.timeout.is_some();
+
+    if status.is_none() && restarts == 0 && !stoppable {
+        /* Only returns on failure */
+        let err = cmd.exec();
+        return Err(anyhow!("Failed to run {} : {}", exe, err));
+    }
+
+    catch_signals(&[
+        libc::SIGINT,
+        libc::SIGTERM,
+        libc::SIGHUP,
+        libc::SIGUSR1,
+        libc::SIGUSR2,
+    ]);
+
+    let st = match attempts(&mut cmd, rank, restarts) {
+        Ok(st) => st,
+        Err(e) => {
+            let st = TaskStatus {
+                job: rank.job.clone(),
+                task,
+                code: Some(127),
+                signal: None,
+                reason: format!("failed to run {} : {}", exe, e),
+                timed_out: false,
+            };
+            if let Some(dir) = status.as_ref() {
+                st.save(dir)?;
+            }
+            return Err(anyhow!("Failed to run {} : {}", exe, e));
+        }
+    };
+
+    if let Some(dir) = status.as_ref() {
+        st.save(dir)?;
+    }
+
+    /* Exit like the command, signals as a shell would report them */
+    std::process::exit(match st.code {
+        Some(c) => c,
+        None => 128 + st.signal.unwrap_or(0),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::launch::tests::{signal_later, SIGNALS};
+    use crate::status::Statuses;
+    use std::os::unix::process::ExitStatusExt;
+
+    fn rank(on_failure: Option<OnFailure>) -> RankPlan {
+        RankPlan {
+            task: 0,
+            host: "n0".to_string(),
+            job: "job0".to_string(),
+            rank: 0,
+            size: 1,
+            pus: vec![0],
+            numas: vec![0],
+            env: Vec::new(),
+            command: Vec::new(),
+            output: None,
+            on_failure,
+            stop_signal: None,
+            grace_period: None,
+            timeout: None,
+        }
+    }
+
+    /// Shell command failing until it ran the given number of times, counted
+    /// in a file
+    fn flaky(name: &str, runs: usize) -> (Command, PathBuf) {
+        let count = std::env::temp_dir().join(format!("lmap-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&count);
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(format!(
+            "echo >> {0}; [ $(wc -l < {0}) -ge {1} ]",
+            count.display(),
+            runs
+        ));
+        (cmd, count)
+    }
+
+    fn runs(count: &PathBuf) -> usize {
+        let ret = std::fs::read_to_string(count).unwrap().lines().count();
+        std::fs::remove_file(count).unwrap();
+        ret
+    }
+
+    #[test]
+    fn failed_command_is_restarted() {
+        let _lock = SIGNALS.lock().unwrap_or_else(|e| e.into_inner());
+        let rank = rank(Some(OnFailure::Restart(2)));
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "exit 1"]);
+
+        let st = attempts(&mut cmd, &rank, 2).unwrap();
+        assert_eq!(st.code, Some(1));
+        assert_eq!(st.reason, "exit code 1 after 2 restart(s)");
+
+        let (mut cmd, count) = flaky("restarts", 10);
+        let st = attempts(&mut cmd, &rank, 2).unwrap();
+        assert!(st.failed());
+        assert_eq!(runs(&count), 3);
+    }
+
+    #[test]
+    fn restarts_stop_once_the_command_succeeds() {
+        let _lock = SIGNALS.lock().unwrap_or_else(|e| e.into_inner());
+        let rank = rank(Some(OnFailure::Restart(3)));
+        let (mut cmd, count) = flaky("success", 2);
+
+        let st = attempts(&mut cmd, &rank, 3).unwrap();
+        assert!(!st.failed());
+        assert_eq!(st.reason, "exit code 0 after 1 restart(s)");
+        assert_eq!(runs(&count), 2);
+    }
+
+    #[test]
+    fn stop_signal_then_kill_after_grace_period() {
+        let _lock = SIGNALS.lock().unwrap_or_else(|e| e.into_inner());
+        catch_signals(&[libc::SIGTERM]);
+
+        let mut rank = rank(None);
+        rank.stop_signal = Some(libc::SIGUSR1);
+        rank.grace_period = Some(Duration::from_secs(1));
+
+        let marker = std::env::temp_dir().join(format!("lmap-usr1-{}", std::process::id()));
+        let _ = std::fs::remove_file(&marker);
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(format!(
+            "trap 'echo USR1 >> {}' USR1; trap '' TERM; while :; do sleep 0.1; done",
+            marker.display()
+        ));
+
+        let start = Instant::now();
+        signal_later(libc::SIGTERM, Duration::from_millis(300));
+        let (st, stop) = supervise(&mut cmd, &rank).unwrap();
+
+        assert_eq!(stop, Some(Stop::Requested));
+        assert_eq!(st.signal(), Some(libc::SIGKILL));
+        assert!(Duration::from_millis(1300) <= start.elapsed());
+        assert_eq!(std::fs::read_to_string(&marker).unwrap().trim(), "USR1");
+        std::fs::remove_file(marker).unwrap();
+    }
+
+    #[test]
+    fn timed_out_task_is_not_restarted() {
+        let _lock = SIGNALS.lock().unwrap_or_else(|e| e.into_inner());
+        let mut rank = rank(Some(OnFailure::Restart(2)));
+        rank.timeout = Some(Duration::from_millis(300));
+        rank.grace_period = Some(Duration::from_secs(1));
+        let mut cmd = Command::new("sleep");
+        cmd.arg("5");
+
+        let start = Instant::now();
+        let (st, stop) = supervise(&mut cmd, &rank).unwrap();
+        assert_eq!(stop, Some(Stop::Timeout));
+        assert_eq!(st.signal(), Some(libc::SIGTERM));
+
+        let st = attempts(&mut cmd, &rank, 2).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert!(st.timed_out && st.failed());
+        assert_eq!(st.code, Some(124));
+        assert!(st.reason.starts_with("timed out after"), "{}", st.reason);
+        assert!(!st.reason.contains("restart"), "{}", st.reason);
+
+        /* Timed out ranks are counted apart from failed ones */
+        let plan = Plan { ranks: vec![rank] };
+        let mut statuses = Statuses::new();
+        statuses.insert(st.key(), st);
+        let err = crate::status::report(&plan, &statuses, true, ExitStatus::from_raw(0), None)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "1 job(s) timed out");
+    }
+}
@@ -0,0 +1,450 @@
+use anyhow::anyhow;
+use anyhow::Result;
+use regex::Regex;
+use serde::Deserialize;
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::joblist::{JobEntry, JobList};
+use crate::status::TaskStatus;
+
+/// Bag of tasks run over the ranks of a job instead of a single command
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct Farm {
+    /// File listing one shell command per line
+    tasks: Option<PathBuf>,
+    /// Files to run the job command on, {} in the command is replaced by each
+    /// of them (appended when absent)
+    glob: Option<String>,
+    /// Tasks running at the same time, defaults to one per rank
+    concurrent: Option<usize>,
+    /// Times a failed task is run again
+    #[serde(default)]
+    retries: usize,
+    /// Log file template of each task, see farm::work
+    log: Option<String>,
+}
+
+impl Farm {
+    pub(crate) fn check(&self, job: &JobEntry) -> Result<()> {
+        match (self.tasks.as_ref(), self.glob.as_ref()) {
+            (Some(_), None) => {
+                if !job.command.is_empty() {
+                    return Err(anyhow!(
+                        "Farm job {} takes its commands from tasks, remove command",
+                        job.label()
+                    ));
+                }
+            }
+            (None, Some(_)) => {
+                if job.command.is_empty() {
+                    return Err(anyhow!(
+                        "Farm job {} needs a command to run on each file",
+                        job.label()
+                    ));
+                }
+            }
+            _ => {
+                return Err(anyhow!(
+                    "Farm job {} needs either tasks or glob",
+                    job.label()
+                ))
+            }
+        }
+
+        if self.concurrent == Some(0) {
+            return Err(anyhow!(
+                "Farm job {} runs 0 concurrent tasks, it has to be strictly positive",
+                job.label()
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Command line of every task
+    fn expand(&self, command: &[String]) -> Result<Vec<Vec<String>>> {
+        if let Some(file) = self.tasks.as_ref() {
+            let data = std::fs::read_to_string(file)
+                .map_err(|e| anyhow!("Failed to read tasks {} : {}", file.display(), e))?;
+
+            return Ok(data
+                .lines()
+                .map(|l| l.trim())
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .map(|l| vec!["sh".to_string(), "-c".to_string(), l.to_string()])
+                .collect());
+        }
+
+        let pattern = self.glob.as_deref().unwrap_or_default();
+
+        Ok(glob(pattern)?
+            .into_iter()
+            .map(|f| {
+                let f = f.display().to_string();
+                if command.iter().any(|a| a.contains("{}")) {
+                    command.iter().map(|a| a.replace("{}", &f)).collect()
+                } else {
+                    let mut ret = command.to_vec();
+                    ret.push(f);
+                    ret
+                }
+            })
+            .collect())
+    }
+}
+
+/// Sorted files matching a pattern, wildcards (* and ?) are only supported in
+/// the file name
+fn glob(pattern: &str) -> Result<Vec<PathBuf>> {
+    let path = Path::new(pattern);
+    let dir = match path.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or(anyhow!("Bad glob {}", pattern))?;
+
+    let re = Regex::new(&format!(
+        "^{}$",
+        regex::escape(name)
+            .replace("\\*", "[^/]*")
+            .replace("\\?", "[^/]")
+    ))?;
+
+    let mut ret: Vec<PathBuf> = std::fs::read_dir(dir)
+        .map_err(|e| anyhow!("Failed to list {} : {}", dir.display(), e))?
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_name().to_str().is_some_and(|n| re.is_match(n)))
+        .map(|e| {
+            if path.parent().is_some_and(|p| !p.as_os_str().is_empty()) {
+                dir.join(e.file_name())
+            } else {
+                PathBuf::from(e.file_name())
+            }
+        })
+        .collect();
+
+    ret.sort();
+
+    if ret.is_empty() {
+        return Err(anyhow!("No file matches {}", pattern));
+    }
+
+    Ok(ret)
+}
+
+/// State directory of a farm job holding its tasks and their claims
+fn state_dir(job: &JobEntry) -> Result<PathBuf> {
+    Ok(std::env::current_dir()?.join(format!("lmap-farm-{}", job.label())))
+}
+
+/// Does the farm job still have unclaimed tasks
+pub(crate) fn pending(job: &JobEntry) -> Result<bool> {
+    let dir = state_dir(job)?;
+    let data = std::fs::read_to_string(dir.join("tasks.json"))
+        .map_err(|e| anyhow!("Failed to read the tasks of {} : {}", dir.display(), e))?;
+    let tasks: Vec<Vec<String>> = serde_json::from_str(&data)?;
+    let claims = std::fs::read_dir(dir.join("claims"))?.count();
+
+    Ok(claims < tasks.len())
+}
+
+/// Write the tasks of every farm job in its state directory and make its
+/// ranks run farm workers
+pub(crate) fn prepare(jobs: &mut JobList) -> Result<()> {
+    for job in jobs.iter_mut() {
+        let farm = match job.farm.as_ref() {
+            Some(f) => f,
+            None => continue,
+        };
+
+        let tasks = farm.expand(&job.command)?;
+
+        /* Claims of a previous run would skip tasks */
+        let dir = state_dir(job)?;
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir)?;
+        }
+        std::fs::create_dir_all(dir.join("claims"))?;
+
+        serde_json::to_writer_pretty(std::fs::File::create(dir.join("tasks.json"))?, &tasks)?;
+
+        println!(
+            "Farm {}: {} task(s) in {}",
+            job.label(),
+            tasks.len(),
+            dir.display()
+        );
+
+        let mut command = vec![
+            std::env::current_exe()?.display().to_string(),
+            "farm".to_string(),
+            "--dir".to_string(),
+            dir.display().to_string(),
+            "--retries".to_string(),
+            farm.retries.to_string(),
+        ];
+
+        if let Some(n) = farm.concurrent {
+            command.extend(["--concurrent".to_string(), n.to_string()]);
+        }
+
+        if let Some(log) = farm.log.as_ref() {
+            command.extend(["--log".to_string(), log.clone()]);
+        }
+
+        job.command = command;
+    }
+
+    Ok(())
+}
+
+/// Farm worker run by each rank of a farm job: claims tasks one at a time
+/// until none is left. Claims are files created in the state directory so that
+/// ranks on every node share the tasks. Logs of each task go to the template,
+/// {job}, {index} and {rank} being expanded, or to <dir>/<index>.log
+pub(crate) fn work(
+    dir: PathBuf,
+    concurrent: Option<usize>,
+    retries: usize,
+    log: Option<String>,
+) -> Result<()> {
+    let env = |v: &str| std::env::var(v).ok();
+
+    let job = env("LMAP_JOB").unwrap_or("farm".to_string());
+    let rank: usize = env("LMAP_JOB_RANK")
+        .and_then(|v| v.parse().ok())
+        .ok_or(anyhow!(
+            "Farm workers run through lmap exec (LMAP_JOB_RANK)"
+        ))?;
+
+    /* Extra ranks stay idle */
+    if concurrent.is_some_and(|n| rank >= n) {
+        return Ok(());
+    }
+
+    let data = std::fs::read_to_string(dir.join("tasks.json"))
+        .map_err(|e| anyhow!("Failed to read the tasks of {} : {}", dir.display(), e))?;
+    let tasks: Vec<Vec<String>> = serde_json::from_str(&data)?;
+
+    let mut ran = 0;
+    let mut failed = 0;
+
+    for (index, task) in tasks.iter().enumerate() {
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(dir.join("claims").join(index.to_string()))
+        {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(anyhow!("Failed to claim task {} : {}", index, e)),
+        }
+
+        let (exe, args) = match task.split_first() {
+            Some(v) => v,
+            None => continue,
+        };
+
+        let out = match log.as_ref() {
+            Some(t) => PathBuf::from(
+                t.replace("{job}", &job)
+                    .replace("{index}", &index.to_string())
+                    .replace("{rank}", &rank.to_string()),
+            ),
+            None => dir.join(format!("{}.log", index)),
+        };
+        if let Some(parent) = out.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = std::fs::File::create(&out)
+            .map_err(|e| anyhow!("Failed to create log {} : {}", out.display(), e))?;
+
+        ran += 1;
+
+        for attempt in 0..=retries {
+            let st = match Command::new(exe)
+                .args(args)
+                .stdout(file.try_clone()?)
+                .stderr(file.try_clone()?)
+                .status()
+            {
+                Ok(st) => TaskStatus::from_exit(index as i32, st),
+                Err(e) => TaskStatus {
+                    code: Some(127),
+                    reason: format!("failed to run {} : {}", exe, e),
+                    ..TaskStatus::from_exit(index as i32, Default::default())
+                },
+            };
+
+            eprintln!(
+                "farm: {} rank {} task {} ({}) {} (attempt {}/{})",
+                job,
+                rank,
+                index,
+                task.join(" "),
+                if st.failed() { &st.reason } else { "done" },
+                attempt + 1,
+                retries + 1
+            );
+
+            if !st.failed() {
+                break;
+            }
+
+            if attempt == retries {
+                failed += 1;
+            }
+        }
+    }
+
+    eprintln!(
+        "farm: {} rank {} ran {} task(s), {} failed",
+        job, rank, ran, failed
+    );
+
+    if failed != 0 {
+        return Err(anyhow!("{} farm task(s) failed", failed));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Empty directory in the temporary directory, removed first if present
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lmap-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn from_yaml(yaml: &str) -> Farm {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn glob_matches_file_names() {
+        let dir = scratch("glob");
+        for f in ["b.raw", "a.raw", "a.txt", "ab.raw", ".raw"] {
+            std::fs::write(dir.join(f), "").unwrap();
+        }
+        std::fs::create_dir(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("sub").join("c.raw"), "").unwrap();
+
+        let names = |pattern: &str| {
+            glob(&format!("{}/{}", dir.display(), pattern))
+                .unwrap()
+                .iter()
+                .map(|p| p.strip_prefix(&dir).unwrap().display().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        /* Sorted, hidden files included, subdirectories not searched */
+        assert_eq!(names("*.raw"), [".raw", "a.raw", "ab.raw", "b.raw"]);
+        assert_eq!(names("?.raw"), ["a.raw", "b.raw"]);
+        assert_eq!(names("a.*"), ["a.raw", "a.txt"]);
+        assert_eq!(names("a.raw"), ["a.raw"]);
+        assert_eq!(names("sub/*"), ["sub/c.raw"]);
+
+        let err = glob(&format!("{}/*.dat", dir.display())).unwrap_err();
+        assert!(err.to_string().starts_with("No file matches"), "{}", err);
+        assert!(glob(&format!("{}/missing/*", dir.display())).is_err());
+
+        /* Relative patterns give relative paths, cargo runs tests in the crate */
+        assert_eq!(glob("Cargo.tom?").unwrap(), [PathBuf::from("Cargo.toml")]);
+        assert!(glob("src/*.rs")
+            .unwrap()
+            .contains(&PathBuf::from("src/farm.rs")));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn expand_tasks_and_files() {
+        let dir = scratch("expand");
+        let tasks = dir.join("tasks.txt");
+        std::fs::write(&tasks, "# setup\n./a 1\n\n   \n  ./b  2 \n#./c\n").unwrap();
+
+        let farm = from_yaml(&format!("tasks: {}", tasks.display()));
+        assert_eq!(
+            farm.expand(&[]).unwrap(),
+            [["sh", "-c", "./a 1"], ["sh", "-c", "./b  2"]]
+        );
+
+        std::fs::write(dir.join("x.raw"), "").unwrap();
+        std::fs::write(dir.join("y.raw"), "").unwrap();
+        let x = dir.join("x.raw").display().to_string();
+        let y = dir.join("y.raw").display().to_string();
+        let farm = from_yaml(&format!("glob: {}/*.raw", dir.display()));
+
+        let command = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            farm.expand(&command(&["conv", "-v"])).unwrap(),
+            [command(&["conv", "-v", &x]), command(&["conv", "-v", &y])]
+        );
+        assert_eq!(
+            farm.expand(&command(&["conv", "--in={}", "{}.out"]))
+                .unwrap(),
+            [
+                command(&["conv", &format!("--in={}", x), &format!("{}.out", x)]),
+                command(&["conv", &format!("--in={}", y), &format!("{}.out", y)]),
+            ]
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn farm_jobs_are_checked() {
+        let job = |farm: &str, command: &str| {
+            JobList::parse(&format!(
+                "- name: bag\n  map: 2slot\n  {}\n  farm:\n    {}\n",
+                command, farm
+            ))
+        };
+
+        job("tasks: t.txt", "").unwrap();
+        job("glob: \"*.raw\"\n    concurrent: 1", "command: [conv]").unwrap();
+
+        for (farm, command, error) in [
+            (
+                "tasks: t.txt",
+                "command: [conv]",
+                "takes its commands from tasks",
+            ),
+            ("glob: \"*.raw\"", "", "needs a command to run on each file"),
+            (
+                "retries: 2",
+                "command: [conv]",
+                "needs either tasks or glob",
+            ),
+            (
+                "tasks: t.txt\n    glob: \"*.raw\"",
+                "",
+                "needs either tasks or glob",
+            ),
+            (
+                "tasks: t.txt\n    concurrent: 0",
+                "",
+                "runs 0 concurrent tasks",
+            ),
+        ] {
+            let err = job(farm, command).unwrap_err();
+            assert!(err.to_string().contains(error), "{}: {}", farm, err);
+        }
+
+        let err =
+            JobList::parse("- map: 2slot\n  on_failure: restart(2)\n  farm:\n    tasks: t.txt\n")
+                .unwrap_err();
+        assert!(err.to_string().contains("cannot be restarted"), "{}", err);
+    }
+}
@@ -2,19 +2,398 @@ use anyhow::anyhow;
 use anyhow::Result;
 use regex::Regex;
 use serde::Deserialize;
-use serde_yaml;
+use serde::Serialize;
 use std::fs;
 use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::exec::DEFAULT_GRACE_PERIOD;
+use crate::farm::Farm;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum RankCount {
+    /// Rank count must be a power of two
+    Pow2,
+    /// Rank count must be a perfect square
+    Square,
+}
+
+#[derive(Deserialize, clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Policy {
+    /// Fill each domain before moving to the next one
+    Compact,
+    /// Spread ranks across nodes first and then across NUMA domains
+    Scatter,
+    /// Round-robin ranks over the domains of the job level
+    Cyclic,
+    /// Contiguous blocks of equal size on each domain of the job level
+    Block,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Exclusive {
+    /// No other job on the NUMA domains of the job
+    Numa,
+    /// No other job on the nodes of the job
+    Node,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Smt {
+    /// Every hardware thread of the slots
+    On,
+    /// Primary hardware thread of each core only
+    Off,
+    /// Sibling hardware threads only, next to the primary ones of smt: off jobs
+    Pair,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum AutoThreads {
+    /// One thread per PU of the rank
+    Auto,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(untagged)]
+pub(crate) enum Threads {
+    Auto(AutoThreads),
+    Count(usize),
+}
+
+/// What happens to the launch when a rank of a job fails
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum OnFailure {
+    /// Kill every job of the launch
+    Abort,
+    /// Let the other jobs finish, the failure does not fail lmap
+    Ignore,
+    /// Run the failed rank again, at most this many times
+    Restart(usize),
+}
+
+impl OnFailure {
+    /// Parse "abort", "ignore", "restart" or "restart(n)"
+    fn parse(policy: &str) -> Result<OnFailure> {
+        let policy = policy.trim();
+
+        match policy {
+            "abort" => return Ok(OnFailure::Abort),
+            "ignore" => return Ok(OnFailure::Ignore),
+            "restart" => return Ok(OnFailure::Restart(1)),
+            _ => {}
+        }
+
+        let count = policy
+            .strip_prefix("restart(")
+            .and_then(|v| v.strip_suffix(')'))
+            .ok_or(anyhow!(
+                "Bad on_failure \"{}\", expected abort, ignore or restart(n)",
+                policy
+            ))?
+            .trim()
+            .parse::<usize>()
+            .map_err(|e| anyhow!("Bad restart count in \"{}\" : {}", policy, e))?;
+
+        Ok(OnFailure::Restart(count))
+    }
+}
+
+/// Parse a duration such as "90", "30s", "10m" or "2h", seconds by default
+fn parse_duration(value: &str) -> Result<Duration> {
+    let value = value.trim();
+    let (count, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => value.split_at(i),
+        None => (value, "s"),
+    };
+
+    let count = count
+        .parse::<u64>()
+        .map_err(|e| anyhow!("Bad duration \"{}\" : {}", value, e))?;
+
+    let unit = match unit.trim() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        _ => {
+            return Err(anyhow!(
+                "Bad duration unit in \"{}\", expected s, m or h",
+                value
+            ))
+        }
+    };
+
+    let secs = count
+        .checked_mul(unit)
+        .ok_or(anyhow!("Bad duration \"{}\" : too large", value))?;
+
+    Ok(Duration::from_secs(secs))
+}
+
+/// Shortest exact form of a duration as parsed by parse_duration
+pub(crate) fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    if secs != 0 && secs.is_multiple_of(3600) {
+        format!("{}h", secs / 3600)
+    } else if secs != 0 && secs.is_multiple_of(60) {
+        format!("{}m", secs / 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// Parse a signal name with or without the SIG prefix, or its number
+fn parse_signal(value: &str) -> Result<i32> {
+    let value = value.trim();
+
+    if let Ok(num) = value.parse::<i32>() {
+        if num <= 0 {
+            return Err(anyhow!(
+                "Bad signal {}, expected a name or a positive number",
+                value
+            ));
+        }
+        return Ok(num);
+    }
+
+    match value.trim_start_matches("SIG") {
+        "HUP" => Ok(libc::SIGHUP),
+        "INT" => Ok(libc::SIGINT),
+        "QUIT" => Ok(libc::SIGQUIT),
+        "KILL" => Ok(libc::SIGKILL),
+        "USR1" => Ok(libc::SIGUSR1),
+        "USR2" => Ok(libc::SIGUSR2),
+        "TERM" => Ok(libc::SIGTERM),
+        "CONT" => Ok(libc::SIGCONT),
+        _ => Err(anyhow!("Unknown signal {}", value)),
+    }
+}
+
+/// Subset of the nodes of the allocation a job is restricted to
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum NodeSelector {
+    /// Node indices in the sorted host list ("0-3,6")
+    Indices(Vec<usize>),
+    /// Host names, possibly in Slurm hostlist form ("nid[001-004]")
+    Hosts(Vec<String>),
+    /// First nodes, either a count or a percentage ("first 2", "first 25%")
+    First(usize, bool),
+    /// Last nodes, either a count or a percentage ("last 1", "last 10%")
+    Last(usize, bool),
+}
+
+impl NodeSelector {
+    fn parse(sel: &str) -> Result<NodeSelector> {
+        let sel = sel.trim();
+
+        if let Some((side, amount)) = sel.split_once(char::is_whitespace) {
+            let amount = amount.trim();
+            let (value, percent) = match amount.strip_suffix('%') {
+                Some(v) => (v, true),
+                None => (amount, false),
+            };
+            let value = value
+                .trim()
+                .parse::<usize>()
+                .map_err(|e| anyhow!("Bad node count in \"{}\" : {}", sel, e))?;
+
+            if value == 0 || (percent && value > 100) {
+                return Err(anyhow!("Node count out of range in \"{}\"", sel));
+            }
+
+            return match side {
+                "first" => Ok(NodeSelector::First(value, percent)),
+                "last" => Ok(NodeSelector::Last(value, percent)),
+                _ => Err(anyhow!(
+                    "Node selection \"{}\" has to start with first or last",
+                    sel
+                )),
+            };
+        }
+
+        if sel.is_empty() {
+            return Err(anyhow!("Empty node selection"));
+        }
+
+        /* Only digits, commas and dashes are node indices */
+        if sel
+            .chars()
+            .all(|c| c.is_ascii_digit() || c == ',' || c == '-')
+        {
+            let mut ret = Vec::new();
+            for (first, last, _) in NodeSelector::ranges(sel)? {
+                ret.extend(first..=last);
+            }
+            return Ok(NodeSelector::Indices(ret));
+        }
+
+        Ok(NodeSelector::Hosts(NodeSelector::expand_hostlist(sel)?))
+    }
+
+    /// Parse "1-3,5" into (first, last, width) ranges, width being the
+    /// zero-padded length of the first bound
+    fn ranges(list: &str) -> Result<Vec<(usize, usize, usize)>> {
+        let mut ret = Vec::new();
+
+        for r in list.split(',') {
+            let (first, last) = r.split_once('-').unwrap_or((r, r));
+            let bad = |e| anyhow!("Bad range {} : {}", r, e);
+            let a = first.parse::<usize>().map_err(bad)?;
+            let b = last.parse::<usize>().map_err(bad)?;
+
+            if b < a {
+                return Err(anyhow!("Bad range {} : bounds are reversed", r));
+            }
+
+            ret.push((a, b, first.len()));
+        }
+
+        Ok(ret)
+    }
+
+    /// Expand a Slurm hostlist such as "nid[001-003,007],login1"
+    fn expand_hostlist(list: &str) -> Result<Vec<String>> {
+        /* Split on commas outside of brackets */
+        let mut items = Vec::new();
+        let mut depth = 0;
+        let mut start = 0;
+
+        for (i, c) in list.char_indices() {
+            match c {
+                '[' => depth += 1,
+                ']' => depth -= 1,
+                ',' if depth == 0 => {
+                    items.push(&list[start..i]);
+                    start = i + 1;
+                }
+                _ => {}
+            }
+        }
+        items.push(&list[start..]);
+
+        let mut ret = Vec::new();
+
+        for item in items {
+            let (prefix, rest) = match item.split_once('[') {
+                Some(v) => v,
+                None => {
+                    if item.is_empty() || item.contains(']') {
+                        return Err(anyhow!("Bad host name \"{}\" in {}", item, list));
+                    }
+                    ret.push(item.to_string());
+                    continue;
+                }
+            };
+
+            let (ranges, suffix) = rest
+                .split_once(']')
+                .ok_or(anyhow!("Unbalanced brackets in {}", list))?;
+
+            for (first, last, width) in NodeSelector::ranges(ranges)? {
+                for i in first..=last {
+                    /* The suffix may hold other bracket groups */
+                    let host = format!("{}{:0width$}{}", prefix, i, suffix, width = width);
+                    ret.extend(NodeSelector::expand_hostlist(&host)?);
+                }
+            }
+        }
+
+        Ok(ret)
+    }
+
+    /// Resolve the selection against the sorted host names of the allocation
+    pub(crate) fn select(&self, hosts: &[String]) -> Result<Vec<String>> {
+        let count = |value: usize, percent: bool| {
+            if percent {
+                (hosts.len() * value).div_ceil(100)
+            } else {
+                value
+            }
+        };
+
+        let ret: Vec<String> = match self {
+            NodeSelector::Indices(idx) => idx
+                .iter()
+                .map(|i| {
+                    hosts.get(*i).cloned().ok_or(anyhow!(
+                        "Node index {} is out of range, the allocation has {} node(s)",
+                        i,
+                        hosts.len()
+                    ))
+                })
+                .collect::<Result<_>>()?,
+            NodeSelector::Hosts(names) => {
+                for n in names.iter() {
+                    if !hosts.contains(n) {
+                        return Err(anyhow!("Node {} is not part of the allocation", n));
+                    }
+                }
+                names.clone()
+            }
+            NodeSelector::First(value, percent) | NodeSelector::Last(value, percent) => {
+                let n = count(*value, *percent);
+                if n > hosts.len() {
+                    return Err(anyhow!(
+                        "Cannot select {} node(s), the allocation has {}",
+                        n,
+                        hosts.len()
+                    ));
+                }
+                match self {
+                    NodeSelector::First(..) => hosts[..n].to_vec(),
+                    _ => hosts[hosts.len() - n..].to_vec(),
+                }
+            }
+        };
+
+        if ret.is_empty() {
+            return Err(anyhow!("Node selection is empty"));
+        }
+
+        Ok(ret)
+    }
+}
 
 #[derive(Deserialize, Debug)]
 struct Job {
+    name: Option<String>,
     map: String,
+    #[serde(default)]
     command: Vec<String>,
+    ranks: Option<RankCount>,
+    ranks_multiple_of: Option<usize>,
+    policy: Option<Policy>,
+    fallback: Option<Policy>,
+    colocate_with: Option<String>,
+    avoid: Option<String>,
+    exclusive: Option<Exclusive>,
+    per: Option<String>,
+    count: Option<usize>,
+    nodes: Option<String>,
+    features: Option<Vec<String>>,
+    cores_per_rank: Option<usize>,
+    threads: Option<Threads>,
+    smt: Option<Smt>,
+    output: Option<String>,
+    on_failure: Option<String>,
+    stop_signal: Option<String>,
+    grace_period: Option<String>,
+    timeout: Option<String>,
+    after: Option<Vec<String>>,
+    elastic: Option<bool>,
+    farm: Option<Farm>,
 }
 
 impl Job {
-    fn parse(&self) -> Result<(String, Option<String>)> {
-        let re = Regex::new("([AE]|[0-9]+)([a-z]+)?")?;
+    /// Split the map into (order, locality, count per domain, strict)
+    fn parse(&self) -> Result<(String, Option<String>, usize, bool)> {
+        let re = Regex::new("^([AE]|[0-9]+)([0-9]+)?([a-z]+)?(!)?$")?;
 
         if let Some(captures) = re.captures(&self.map) {
             let ord = match captures.get(1) {
@@ -22,7 +401,18 @@ impl Job {
                 None => return Err(anyhow!("Failed to parse ordering")),
             };
 
-            let loc = captures.get(2).map(|v| v.as_str().to_string());
+            let mut loc = captures.get(3).map(|v| v.as_str().to_string());
+
+            /* per: and count: are the long form of E<count><level> */
+            if let Some(per) = self.per.as_ref() {
+                if ord != "E" {
+                    return Err(anyhow!("per: {} requires an E job specifier", per));
+                }
+                if loc.as_ref().is_some_and(|l| l != per) {
+                    return Err(anyhow!("per: {} conflicts with map {}", per, self.map));
+                }
+                loc = Some(per.clone());
+            }
 
             if let Some(l) = loc.clone() {
                 match l.as_str() {
@@ -33,36 +423,318 @@ impl Job {
                 };
             }
 
-            if ord == "E" {
-                if loc.is_none() {
-                    return Err(anyhow!("E job specifier requires a locality specifier"));
+            if ord == "E" && loc.is_none() {
+                return Err(anyhow!(
+                    "E job specifier requires a locality specifier or per:"
+                ));
+            }
+
+            let count = match captures.get(2) {
+                Some(c) if ord != "E" => {
+                    return Err(anyhow!(
+                        "Only E job specifiers take a count per domain ({} in {})",
+                        c.as_str(),
+                        self.map
+                    ));
                 }
+                Some(c) => {
+                    if self.count.is_some() {
+                        return Err(anyhow!("count: conflicts with map {}", self.map));
+                    }
+                    c.as_str().parse::<usize>()?
+                }
+                None => match self.count {
+                    Some(_) if self.per.is_none() => {
+                        return Err(anyhow!("count: requires per: in job {}", self.map));
+                    }
+                    Some(c) => c,
+                    None => 1,
+                },
+            };
+
+            if count == 0 || (count != 1 && loc.as_deref() == Some("slot")) {
+                return Err(anyhow!(
+                    "Bad count per domain in {}, it has to be strictly positive and 1 for slots",
+                    self.map
+                ));
             }
 
-            return Ok((ord.to_string(), loc));
+            let strict = captures.get(4).is_some();
+
+            if strict && ord != "E" {
+                return Err(anyhow!(
+                    "Only E job specifiers can be strict (! in {})",
+                    self.map
+                ));
+            }
+
+            if self.ranks_multiple_of == Some(0) {
+                return Err(anyhow!("ranks_multiple_of must be strictly positive"));
+            }
+
+            return Ok((ord.to_string(), loc, count, strict));
         }
 
         Err(anyhow!("Bad syntax in {}", self.map))
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct JobEntry {
+    pub(crate) id: u32,
+    pub(crate) name: Option<String>,
     pub(crate) map: String,
     pub(crate) order: String,
     pub(crate) loc: Option<String>,
+    /// Slots per domain for E jobs
+    pub(crate) count: usize,
+    /// E jobs requiring every domain to host them
+    pub(crate) strict: bool,
     pub(crate) command: Vec<String>,
+    pub(crate) ranks: Option<RankCount>,
+    pub(crate) ranks_multiple_of: Option<usize>,
+    pub(crate) policy: Option<Policy>,
+    pub(crate) fallback: Option<Policy>,
+    pub(crate) colocate_with: Option<String>,
+    pub(crate) avoid: Option<String>,
+    pub(crate) exclusive: Option<Exclusive>,
+    pub(crate) nodes: Option<NodeSelector>,
+    /// Slurm features every node of the job must provide
+    pub(crate) features: Vec<String>,
+    /// Adjacent slots of a NUMA domain merged into each rank
+    pub(crate) cores_per_rank: usize,
+    pub(crate) threads: Option<Threads>,
+    pub(crate) smt: Smt,
+    /// Output file template of each rank when launched with lmap exec
+    pub(crate) output: Option<String>,
+    /// Failure policy, failed ranks fail lmap once the others are done if unset
+    pub(crate) on_failure: Option<OnFailure>,
+    /// Signal the exec wrapper sends to the command when lmap is stopped
+    pub(crate) stop_signal: Option<i32>,
+    /// Time left to the command after the stop signal before it is killed
+    pub(crate) grace_period: Option<Duration>,
+    /// Wall-time limit of each rank, enforced by the exec wrapper
+    pub(crate) timeout: Option<Duration>,
+    /// Names of the jobs which have to end before this one starts
+    pub(crate) after: Vec<String>,
+    /// A job launched again on slots freed by other jobs with --dynamic
+    pub(crate) elastic: bool,
+    /// Tasks shared by the ranks instead of a single command
+    pub(crate) farm: Option<Farm>,
 }
 
 impl JobEntry {
-    fn from_job(job: Job) -> Result<JobEntry> {
+    fn from_job(job: Job, id: u32) -> Result<JobEntry> {
         let parsed_map = job.parse()?;
-        Ok(JobEntry {
+        let ret = JobEntry {
+            id,
+            name: job.name,
             map: job.map,
             order: parsed_map.0,
             loc: parsed_map.1,
+            count: parsed_map.2,
+            strict: parsed_map.3,
             command: job.command,
-        })
+            ranks: job.ranks,
+            ranks_multiple_of: job.ranks_multiple_of,
+            policy: job.policy,
+            fallback: job.fallback,
+            colocate_with: job.colocate_with,
+            avoid: job.avoid,
+            exclusive: job.exclusive,
+            nodes: match job.nodes.as_deref() {
+                Some(sel) => Some(NodeSelector::parse(sel)?),
+                None => None,
+            },
+            features: job.features.unwrap_or_default(),
+            cores_per_rank: job.cores_per_rank.unwrap_or(1),
+            threads: job.threads,
+            smt: job.smt.unwrap_or(Smt::On),
+            output: job.output,
+            on_failure: match job.on_failure.as_deref() {
+                Some(p) => Some(OnFailure::parse(p)?),
+                None => None,
+            },
+            stop_signal: match job.stop_signal.as_deref() {
+                Some(s) => Some(parse_signal(s)?),
+                None => None,
+            },
+            grace_period: match job.grace_period.as_deref() {
+                Some(g) => Some(parse_duration(g)?),
+                None => None,
+            },
+            timeout: match job.timeout.as_deref() {
+                Some(t) => Some(parse_duration(t)?),
+                None => None,
+            },
+            after: job.after.unwrap_or_default(),
+            elastic: job.elastic.unwrap_or(false),
+            farm: job.farm,
+        };
+
+        match ret.farm.as_ref() {
+            Some(farm) => farm.check(&ret)?,
+            None if ret.command.is_empty() => {
+                return Err(anyhow!("Job {} has no command", ret.label()));
+            }
+            None => {}
+        }
+
+        if ret.farm.is_some() && matches!(ret.on_failure, Some(OnFailure::Restart(_))) {
+            return Err(anyhow!(
+                "Farm job {} cannot be restarted, use the retries of its farm",
+                ret.label()
+            ));
+        }
+
+        if ret.elastic && ret.order != "A" {
+            return Err(anyhow!(
+                "Job {} is elastic, only A jobs can be",
+                ret.label()
+            ));
+        }
+
+        if ret.timeout == Some(Duration::ZERO) {
+            return Err(anyhow!(
+                "Job {} has a zero timeout, it has to be strictly positive",
+                ret.label()
+            ));
+        }
+
+        if ret.threads == Some(Threads::Count(0)) {
+            return Err(anyhow!(
+                "Job {} requests 0 threads, use auto or a strictly positive count",
+                ret.label()
+            ));
+        }
+
+        if ret.cores_per_rank == 0 {
+            return Err(anyhow!(
+                "Job {} requests 0 cores per rank, it has to be strictly positive",
+                ret.label()
+            ));
+        }
+
+        /* Fixed jobs have a known size we can check right away */
+        if let Ok(size) = ret.order.parse::<usize>() {
+            if !ret.accepts_rank_count(size) {
+                return Err(anyhow!(
+                    "Job {} requests {} ranks which does not satisfy its rank constraints",
+                    ret.label(),
+                    size
+                ));
+            }
+        }
+
+        Ok(ret)
+    }
+
+    /// Number of slots requested by a fixed job
+    pub(crate) fn fixed_size(&self) -> Result<usize> {
+        match self.order.parse::<usize>() {
+            Ok(num) => Ok(num),
+            Err(e) => Err(anyhow!(
+                "Failed to parse value for fixed alloc {} : {}",
+                self.order,
+                e
+            )),
+        }
+    }
+
+    /// Name of the job, falls back to its position in the jobfile
+    pub(crate) fn label(&self) -> String {
+        match self.name.as_ref() {
+            Some(n) => n.clone(),
+            None => format!("job{}", self.id),
+        }
+    }
+
+    /// Check a rank count against the job constraints (if any)
+    pub(crate) fn accepts_rank_count(&self, count: usize) -> bool {
+        if let Some(mult) = self.ranks_multiple_of {
+            if !count.is_multiple_of(mult) {
+                return false;
+            }
+        }
+
+        match self.ranks {
+            Some(RankCount::Pow2) => count.is_power_of_two(),
+            Some(RankCount::Square) => {
+                let root = (count as f64).sqrt().round() as usize;
+                root * root == count
+            }
+            None => true,
+        }
+    }
+
+    /// Largest rank count lower or equal to max satisfying the constraints
+    pub(crate) fn fit_rank_count(&self, max: usize) -> usize {
+        (1..=max)
+            .rev()
+            .find(|v| self.accepts_rank_count(*v))
+            .unwrap_or(0)
+    }
+
+    /// Placement policy, defaults to compact for slots and cyclic for nodes and NUMA
+    pub(crate) fn policy(&self) -> Policy {
+        match (self.policy, self.loc.as_deref()) {
+            (Some(p), _) => p,
+            (None, Some("node")) | (None, Some("numa")) => Policy::Cyclic,
+            (None, _) => Policy::Compact,
+        }
+    }
+
+    /// Thread settings, ranks spanning several cores default to auto
+    pub(crate) fn threads(&self) -> Option<Threads> {
+        match self.threads {
+            Some(t) => Some(t),
+            None if self.cores_per_rank != 1 => Some(Threads::Auto(AutoThreads::Auto)),
+            None => None,
+        }
+    }
+
+    /// Threading environment of a rank running on the given PUs
+    pub(crate) fn thread_env(&self, pus: &[usize]) -> Vec<String> {
+        let count = match self.threads() {
+            Some(Threads::Count(n)) => n,
+            Some(Threads::Auto(_)) => pus.len().max(1),
+            None => return Vec::new(),
+        };
+
+        let list = pus
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let places = pus
+            .iter()
+            .map(|v| format!("{{{}}}", v))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        vec![
+            format!("OMP_NUM_THREADS={}", count),
+            format!("OMP_PLACES={}", places),
+            "OMP_PROC_BIND=close".to_string(),
+            format!("GOMP_CPU_AFFINITY={}", list),
+            format!("KMP_AFFINITY=granularity=fine,explicit,proclist=[{}]", list),
+            format!("MKL_NUM_THREADS={}", count),
+        ]
+    }
+
+    /// Restarts, stop policies and timeouts are handled by the exec wrapper,
+    /// farm workers need the job rank it sets
+    pub(crate) fn needs_wrapper(&self) -> bool {
+        matches!(self.on_failure, Some(OnFailure::Restart(_)))
+            || self.farm.is_some()
+            || self.stop_signal.is_some()
+            || self.grace_period.is_some()
+            || self.timeout.is_some()
+    }
+
+    pub(crate) fn has_rank_constraint(&self) -> bool {
+        self.ranks.is_some() || self.ranks_multiple_of.is_some()
     }
 
     pub(crate) fn loc_or_slot(&self) -> String {
@@ -82,35 +754,245 @@ pub(crate) struct JobList {
 
 impl JobList {
     pub(crate) fn load(file: PathBuf) -> Result<JobList> {
-        let deserialized_jobs: Vec<Job> = match fs::read_to_string(file) {
-            Ok(s) => match serde_yaml::from_str(&s) {
-                Ok(j) => j,
-                Err(e) => return Err(anyhow!(e)),
-            },
+        match fs::read_to_string(file) {
+            Ok(s) => JobList::parse(&s),
+            Err(e) => Err(anyhow!(e)),
+        }
+    }
+
+    /// Jobs of a jobfile given as YAML
+    pub(crate) fn parse(yaml: &str) -> Result<JobList> {
+        let deserialized_jobs: Vec<Job> = match serde_yaml::from_str(yaml) {
+            Ok(j) => j,
             Err(e) => return Err(anyhow!(e)),
         };
 
         let mut jobs: Vec<JobEntry> = Vec::new();
 
-        for j in deserialized_jobs {
-            jobs.push(JobEntry::from_job(j)?);
+        for (id, j) in deserialized_jobs.into_iter().enumerate() {
+            jobs.push(JobEntry::from_job(j, id as u32)?);
         }
 
-        Ok(JobList { jobs })
+        let ret = JobList { jobs };
+        ret.check_names()?;
+        ret.check_constraints()?;
+
+        Ok(ret)
     }
 
-    pub(crate) fn job_id(&self, job: &JobEntry) -> Result<u32> {
-        for (id, j) in self.jobs.iter().enumerate() {
-            if std::ptr::eq(j, job) {
-                return Ok(id as u32);
+    /// Names have to be unique and usable in file names and labels
+    fn check_names(&self) -> Result<()> {
+        let unnamed = Regex::new(r"^job[0-9]+$")?;
+
+        for j in self.jobs.iter() {
+            let name = match j.name.as_ref() {
+                Some(n) => n,
+                None => continue,
+            };
+
+            if name.is_empty() || name.contains(|c: char| c.is_whitespace() || c == '/') {
+                return Err(anyhow!(
+                    "Job name \"{}\" must be non-empty without spaces or slashes",
+                    name
+                ));
+            }
+
+            /* Would collide with the label of an unnamed job */
+            if unnamed.is_match(name) {
+                return Err(anyhow!(
+                    "Job name {} is reserved for unnamed jobs (jobN), pick another one",
+                    name
+                ));
+            }
+
+            /* Would collide with an instance of an elastic job (label.N) */
+            if let Some((base, n)) = name.rsplit_once('.') {
+                if !n.is_empty()
+                    && n.chars().all(|c| c.is_ascii_digit())
+                    && self.jobs.iter().any(|o| o.elastic && o.label() == base)
+                {
+                    return Err(anyhow!(
+                        "Job name {} is reserved for instances of elastic job {} ({}.N), pick another one",
+                        name,
+                        base,
+                        base
+                    ));
+                }
+            }
+
+            if self
+                .jobs
+                .iter()
+                .any(|o| o.id != j.id && o.name.as_ref() == Some(name))
+            {
+                return Err(anyhow!("Job name {} is used more than once", name));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Make sure affinity constraints reference existing jobs and do not conflict
+    fn check_constraints(&self) -> Result<()> {
+        for j in self.jobs.iter() {
+            for target in [j.colocate_with.as_ref(), j.avoid.as_ref()]
+                .into_iter()
+                .flatten()
+                .chain(j.after.iter())
+            {
+                if self.job_by_name(target)?.id == j.id {
+                    return Err(anyhow!("Job {} references itself", j.label()));
+                }
+            }
+
+            if j.colocate_with.is_some() && j.colocate_with == j.avoid {
+                return Err(anyhow!(
+                    "Job {} cannot both colocate with and avoid {}",
+                    j.label(),
+                    j.avoid.as_ref().unwrap()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Split the jobs into stages run one after the other, a job runs in the
+    /// stage following the last stage of the jobs it comes after
+    pub(crate) fn into_stages(self) -> Result<Vec<JobList>> {
+        let mut stage: Vec<Option<usize>> = vec![None; self.jobs.len()];
+
+        /* Each pass places the jobs whose dependencies are all placed */
+        while stage.iter().any(|s| s.is_none()) {
+            let mut progress = false;
+
+            for (i, j) in self.jobs.iter().enumerate() {
+                if stage[i].is_some() {
+                    continue;
+                }
+
+                let deps = j
+                    .after
+                    .iter()
+                    .map(|name| {
+                        let dep = self.job_by_name(name)?;
+                        Ok(self
+                            .jobs
+                            .iter()
+                            .position(|v| v.id == dep.id)
+                            .and_then(|p| stage[p]))
+                    })
+                    .collect::<Result<Option<Vec<usize>>>>()?;
+
+                if let Some(deps) = deps {
+                    stage[i] = Some(deps.iter().map(|s| s + 1).max().unwrap_or(0));
+                    progress = true;
+                }
+            }
+
+            if !progress {
+                let cycle = self
+                    .jobs
+                    .iter()
+                    .zip(stage.iter())
+                    .filter(|(_, s)| s.is_none())
+                    .map(|(j, _)| j.label())
+                    .collect::<Vec<_>>();
+                return Err(anyhow!(
+                    "Jobs {} wait for each other (cycle in after)",
+                    cycle.join(", ")
+                ));
+            }
+        }
+
+        let stage: Vec<usize> = stage.into_iter().flatten().collect();
+
+        /* Affinity constraints only make sense between jobs running together */
+        for (i, j) in self.jobs.iter().enumerate() {
+            for target in [j.colocate_with.as_ref(), j.avoid.as_ref()]
+                .into_iter()
+                .flatten()
+            {
+                let dep = self.job_by_name(target)?;
+                let other = self.jobs.iter().position(|v| v.id == dep.id).unwrap();
+                if stage[other] != stage[i] {
+                    return Err(anyhow!(
+                        "Job {} references {} which runs in another stage",
+                        j.label(),
+                        target
+                    ));
+                }
             }
         }
 
-        Err(anyhow!("No such job in list"))
+        let count = stage.iter().max().map_or(0, |s| s + 1);
+        let mut ret: Vec<JobList> = (0..count).map(|_| JobList { jobs: Vec::new() }).collect();
+
+        for (j, s) in self.jobs.into_iter().zip(stage) {
+            ret[s].jobs.push(j);
+        }
+
+        Ok(ret)
+    }
+
+    /// List of jobs already checked by JobList::load
+    pub(crate) fn from_jobs(jobs: Vec<JobEntry>) -> JobList {
+        JobList { jobs }
+    }
+
+    pub(crate) fn into_jobs(self) -> Vec<JobEntry> {
+        self.jobs
+    }
+
+    /// Longest time a job may take to stop
+    pub(crate) fn grace_period(&self) -> Duration {
+        self.jobs
+            .iter()
+            .map(|j| j.grace_period.unwrap_or(DEFAULT_GRACE_PERIOD))
+            .max()
+            .unwrap_or(DEFAULT_GRACE_PERIOD)
+    }
+
+    pub(crate) fn job_by_name(&self, name: &str) -> Result<&JobEntry> {
+        self.jobs
+            .iter()
+            .find(|j| j.name.as_deref() == Some(name))
+            .ok_or(anyhow!("No job named {} in list", name))
+    }
+
+    /// Jobs which declared they avoid the given job
+    pub(crate) fn avoiding<'a>(&'a self, job: &'a JobEntry) -> impl Iterator<Item = &'a JobEntry> {
+        self.jobs
+            .iter()
+            .filter(move |j| job.name.is_some() && j.avoid == job.name)
+    }
+
+    /// Set the policy of jobs which do not define one
+    pub(crate) fn set_default_policy(&mut self, policy: Policy) {
+        for j in self.jobs.iter_mut() {
+            if j.policy.is_none() {
+                j.policy = Some(policy);
+            }
+        }
+    }
+
+    /// Run the same command for every job
+    pub(crate) fn set_command(&mut self, command: Vec<String>) {
+        for j in self.jobs.iter_mut() {
+            j.command = command.clone();
+        }
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &JobEntry> {
+        self.jobs.iter()
+    }
+
+    pub(crate) fn iter_mut(&mut self) -> impl Iterator<Item = &mut JobEntry> {
+        self.jobs.iter_mut()
     }
 
     pub(crate) fn job_by_id(&self, id: u32) -> Option<&JobEntry> {
-        self.jobs.get(id as usize)
+        self.jobs.iter().find(|j| j.id == id)
     }
 
     pub(crate) fn fixed_jobs(&self) -> impl Iterator<Item = &JobEntry> {
@@ -120,7 +1002,7 @@ impl JobList {
     }
 
     pub(crate) fn all_jobs(&self) -> impl Iterator<Item = &JobEntry> {
-        self.jobs.iter().filter(|v| (v.order == "A"))
+        self.jobs.iter().filter(|v| v.order == "A")
     }
 
     pub(crate) fn all_jobs_count(&self) -> usize {
@@ -128,6 +1010,258 @@ impl JobList {
     }
 
     pub(crate) fn each_jobs(&self) -> impl Iterator<Item = &JobEntry> {
-        self.jobs.iter().filter(|v| (v.order == "E"))
+        self.jobs.iter().filter(|v| v.order == "E")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_of_unnamed_jobs_are_reserved() {
+        let err = JobList::parse(
+            "- map: 1slot\n  command: [a]\n- name: job0\n  map: 1slot\n  command: [b]\n",
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("reserved"), "{}", err);
+
+        let jobs = JobList::parse(
+            "- map: 1slot\n  command: [a]\n- name: job0a\n  map: 1slot\n  command: [b]\n",
+        )
+        .unwrap();
+        let labels: Vec<String> = jobs.iter().map(|j| j.label()).collect();
+        assert_eq!(labels, ["job0", "job0a"]);
+    }
+
+    #[test]
+    fn names_of_elastic_instances_are_reserved() {
+        for (elastic, name) in [("name: fill", "fill.1"), ("", "job0.12")] {
+            let err = JobList::parse(&format!(
+                "- map: Aslot\n  command: [a]\n  elastic: true\n  {}\n- name: {}\n  map: 1slot\n  command: [b]\n",
+                elastic, name
+            ))
+            .unwrap_err();
+            assert!(
+                err.to_string()
+                    .contains("reserved for instances of elastic job"),
+                "{}",
+                err
+            );
+        }
+
+        for name in ["fill.a", "fill.", "other.1"] {
+            JobList::parse(&format!(
+                "- name: fill\n  map: Aslot\n  command: [a]\n  elastic: true\n- name: {}\n  map: 1slot\n  command: [b]\n",
+                name
+            ))
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn failure_policies() {
+        assert_eq!(OnFailure::parse("abort").unwrap(), OnFailure::Abort);
+        assert_eq!(OnFailure::parse("ignore").unwrap(), OnFailure::Ignore);
+        assert_eq!(OnFailure::parse("restart").unwrap(), OnFailure::Restart(1));
+        assert_eq!(
+            OnFailure::parse("restart(3)").unwrap(),
+            OnFailure::Restart(3)
+        );
+        for bad in ["retry", "restart(", "restart(x)", "restart(-1)"] {
+            assert!(OnFailure::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn signals() {
+        assert_eq!(parse_signal("USR1").unwrap(), libc::SIGUSR1);
+        assert_eq!(parse_signal("SIGUSR2").unwrap(), libc::SIGUSR2);
+        assert_eq!(parse_signal(" TERM ").unwrap(), libc::SIGTERM);
+        assert_eq!(parse_signal("10").unwrap(), 10);
+        for bad in ["", "0", "-9", "SIGFOO", "usr1"] {
+            assert!(parse_signal(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("10m").unwrap(), Duration::from_secs(600));
+        assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(7200));
+        for bad in [
+            "",
+            "m",
+            "5d",
+            "-1s",
+            "18446744073709551615m",
+            "9999999999999999h",
+        ] {
+            assert!(parse_duration(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn rank_constraints() {
+        /* Constraints, accepted counts, rejected counts, (max, fit) pairs */
+        type Case<'a> = (&'a str, &'a [usize], &'a [usize], &'a [(usize, usize)]);
+        let table: [Case; 4] = [
+            (
+                "ranks: pow2",
+                &[1, 2, 4, 64],
+                &[0, 3, 6, 12],
+                &[(0, 0), (1, 1), (7, 4), (16, 16)],
+            ),
+            (
+                "ranks: square",
+                &[1, 4, 9, 144],
+                &[2, 8, 10],
+                &[(3, 1), (10, 9), (24, 16)],
+            ),
+            (
+                "ranks_multiple_of: 3",
+                &[3, 6, 99],
+                &[1, 4, 7],
+                &[(2, 0), (7, 6), (9, 9)],
+            ),
+            (
+                "ranks: pow2\n  ranks_multiple_of: 4",
+                &[4, 8, 16],
+                &[2, 12],
+                &[(3, 0), (12, 8)],
+            ),
+        ];
+
+        for (constraints, accepted, rejected, fits) in table {
+            let jobs = JobList::parse(&format!(
+                "- map: Aslot\n  command: [a]\n  {}\n",
+                constraints
+            ))
+            .unwrap();
+            let job = jobs.iter().next().unwrap();
+
+            for count in accepted {
+                assert!(job.accepts_rank_count(*count), "{} {}", constraints, count);
+            }
+            for count in rejected {
+                assert!(!job.accepts_rank_count(*count), "{} {}", constraints, count);
+            }
+            for (max, fit) in fits {
+                assert_eq!(job.fit_rank_count(*max), *fit, "{} {}", constraints, max);
+            }
+        }
+    }
+
+    fn hosts(list: &str) -> Vec<String> {
+        NodeSelector::expand_hostlist(list).unwrap()
+    }
+
+    #[test]
+    fn hostlists() {
+        assert_eq!(
+            hosts("nid[001-004]"),
+            ["nid001", "nid002", "nid003", "nid004"]
+        );
+        assert_eq!(hosts("n[8-10]"), ["n8", "n9", "n10"]);
+        assert_eq!(hosts("nid[01,03-04]"), ["nid01", "nid03", "nid04"]);
+        assert_eq!(hosts("r[1-2]n[1-2]"), ["r1n1", "r1n2", "r2n1", "r2n2"]);
+        assert_eq!(
+            hosts("login1,nid[1-2],gpu[3]-ib"),
+            ["login1", "nid1", "nid2", "gpu3-ib"]
+        );
+
+        for (list, error) in [
+            ("nid[3-1]", "reversed"),
+            ("nid[]", "Bad range"),
+            ("nid[1-]", "Bad range"),
+            ("nid[1-2", "Unbalanced"),
+            ("nid1-2]", "Bad host name"),
+            ("login1,,nid1", "Bad host name"),
+        ] {
+            let err = NodeSelector::expand_hostlist(list).unwrap_err();
+            assert!(err.to_string().contains(error), "{}: {}", list, err);
+        }
+    }
+
+    #[test]
+    fn node_selectors() {
+        let parse = |sel| NodeSelector::parse(sel).unwrap();
+
+        assert_eq!(parse("0,2-3"), NodeSelector::Indices(vec![0, 2, 3]));
+        assert_eq!(parse("first 25%"), NodeSelector::First(25, true));
+        assert_eq!(parse(" last  2 "), NodeSelector::Last(2, false));
+        assert_eq!(
+            parse("n[1-2]"),
+            NodeSelector::Hosts(vec!["n1".to_string(), "n2".to_string()])
+        );
+
+        for sel in ["", "first 0", "last 101%", "middle 2", "first two", "2-1"] {
+            assert!(NodeSelector::parse(sel).is_err(), "{}", sel);
+        }
+
+        let nodes: Vec<String> = (0..5).map(|i| format!("n{}", i)).collect();
+        let select = |sel| parse(sel).select(&nodes);
+
+        /* 25% of 5 nodes rounds up to 2 */
+        assert_eq!(select("first 25%").unwrap(), ["n0", "n1"]);
+        assert_eq!(select("last 1%").unwrap(), ["n4"]);
+        assert_eq!(select("last 2").unwrap(), ["n3", "n4"]);
+        assert_eq!(select("n[1,3]").unwrap(), ["n1", "n3"]);
+
+        for (sel, error) in [
+            ("last 6", "Cannot select 6 node(s), the allocation has 5"),
+            ("1,5", "Node index 5 is out of range"),
+            ("n7", "Node n7 is not part of the allocation"),
+        ] {
+            let err = select(sel).unwrap_err();
+            assert!(err.to_string().contains(error), "{}: {}", sel, err);
+        }
+    }
+
+    fn stages(yaml: &str) -> Result<Vec<Vec<String>>> {
+        Ok(JobList::parse(yaml)?
+            .into_stages()?
+            .into_iter()
+            .map(|s| s.iter().map(|j| j.label()).collect())
+            .collect())
+    }
+
+    #[test]
+    fn stages_follow_after() {
+        /* The README workflow, post listed first */
+        let workflow = stages(
+            "- name: post\n  map: 1node\n  command: [d]\n  after: [sim, ana]\n\
+             - name: pre\n  map: Enode\n  command: [a]\n\
+             - name: sim\n  map: Anode\n  command: [b]\n  after: [pre]\n\
+             - name: ana\n  map: 2numa\n  command: [c]\n  after: [pre]\n  colocate_with: sim\n",
+        )
+        .unwrap();
+        assert_eq!(workflow, [vec!["pre"], vec!["sim", "ana"], vec!["post"]]);
+
+        let err = stages(
+            "- name: a\n  map: 1slot\n  command: [a]\n  after: [c]\n\
+             - name: b\n  map: 1slot\n  command: [b]\n  after: [a]\n\
+             - name: c\n  map: 1slot\n  command: [c]\n  after: [b]\n\
+             - name: d\n  map: 1slot\n  command: [d]\n",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Jobs a, b, c wait for each other (cycle in after)"
+        );
+
+        for affinity in ["colocate_with", "avoid"] {
+            let err = stages(&format!(
+                "- name: pre\n  map: 1slot\n  command: [a]\n\
+                 - name: sim\n  map: 1slot\n  command: [b]\n  after: [pre]\n  {}: pre\n",
+                affinity
+            ))
+            .unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "Job sim references pre which runs in another stage"
+            );
+        }
     }
 }
@@ -0,0 +1,454 @@
+use anyhow::anyhow;
+use anyhow::Result;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+use crate::exec::{Plan, RankPlan};
+use crate::joblist::OnFailure;
+use crate::status::{Statuses, TaskStatus};
+
+/// How steps are started and how long they get to stop
+#[derive(Debug, Clone)]
+pub(crate) struct Launcher {
+    /// Program starting the steps
+    pub(crate) program: String,
+    /// Time srun gets on top of the grace period of the jobs to stop its
+    /// tasks, and again to exit once cancelled
+    pub(crate) margin: Duration,
+    /// Where plans, host files and task statuses are written
+    pub(crate) dir: PathBuf,
+}
+
+impl Launcher {
+    /// Steps started by srun writing their files in the given directory
+    pub(crate) fn new(dir: PathBuf) -> Launcher {
+        Launcher {
+            program: "srun".to_string(),
+            margin: Duration::from_secs(5),
+            dir,
+        }
+    }
+}
+
+/// Last signal received, 0 when none is pending
+static SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn on_signal(sig: libc::c_int) {
+    SIGNAL.store(sig, Ordering::SeqCst);
+}
+
+/// Record the given signals instead of dying from them, see take_signal
+pub(crate) fn catch_signals(signals: &[i32]) {
+    for sig in signals {
+        unsafe {
+            libc::signal(
+                *sig,
+                on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            );
+        }
+    }
+}
+
+/// Signal received since the last call, if any
+pub(crate) fn take_signal() -> Option<i32> {
+    match SIGNAL.swap(0, Ordering::SeqCst) {
+        0 => None,
+        sig => Some(sig),
+    }
+}
+
+/// Send a signal to a process, or to a process group if pid is negative
+pub(crate) fn send_signal(pid: i32, sig: i32) -> Result<()> {
+    if unsafe { libc::kill(pid, sig) } != 0 {
+        return Err(anyhow!(
+            "Failed to send signal {} to {} : {}",
+            sig,
+            pid,
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+/// An srun step supervised by lmap
+pub(crate) struct Step {
+    /// Job launched alone in the step, tasks are then numbered by job rank.
+    /// None for a multi-prog step whose tasks are numbered like the plan
+    pub(crate) job: Option<String>,
+    /// Ranks of the plan the step runs
+    ranks: Vec<RankPlan>,
+    srun: Child,
+}
+
+impl Step {
+    /// Launch the tasks of a multi-prog configuration file
+    pub(crate) fn multi_prog(launcher: &Launcher, file: &Path, plan: &Plan) -> Result<Step> {
+        let srun = Command::new(&launcher.program)
+            .arg("--multi-prog")
+            .arg(file)
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        Ok(Step {
+            job: None,
+            ranks: plan.ranks.clone(),
+            srun,
+        })
+    }
+
+    /// Launch the ranks of a job through the exec wrapper, each rank on the
+    /// host the plan gives it. The step overlaps the others and leaves CPU
+    /// binding to the wrapper: Slurm masks apply to local task ids on every
+    /// node alike and cannot follow the mapping
+    pub(crate) fn job(
+        launcher: &Launcher,
+        plan: &Plan,
+        job: &str,
+        wrapper: &[String],
+    ) -> Result<Step> {
+        let mut ranks: Vec<RankPlan> = plan
+            .ranks
+            .iter()
+            .filter(|r| r.job == job)
+            .cloned()
+            .collect();
+        ranks.sort_by_key(|r| r.rank);
+
+        let mut hosts: Vec<&str> = Vec::new();
+        for r in ranks.iter() {
+            if !hosts.contains(&r.host.as_str()) {
+                hosts.push(&r.host);
+            }
+        }
+
+        /* One line per task with the arbitrary distribution */
+        let hostfile = launcher.dir.join(format!("lmap-{}.hosts", job));
+        std::fs::write(
+            &hostfile,
+            ranks
+                .iter()
+                .map(|r| format!("{}\n", r.host))
+                .collect::<String>(),
+        )?;
+
+        let srun = Command::new(&launcher.program)
+            .env("SLURM_HOSTFILE", &hostfile)
+            .arg("--overlap")
+            .arg("--cpu-bind=none")
+            .arg("--distribution=arbitrary")
+            .arg(format!("--job-name={}", job))
+            .arg(format!("--nodes={}", hosts.len()))
+            .arg(format!("--ntasks={}", ranks.len()))
+            .arg(format!("--nodelist={}", hosts.join(",")))
+            .args(wrapper)
+            .args(["--job", job])
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        Ok(Step {
+            job: Some(job.to_string()),
+            ranks,
+            srun,
+        })
+    }
+
+    /// Rank of the plan behind a task number reported by srun
+    fn rank(&self, task: i32) -> Option<&RankPlan> {
+        match self.job {
+            Some(_) => self.ranks.iter().find(|r| r.rank as i32 == task),
+            None => self.ranks.iter().find(|r| r.task == task),
+        }
+    }
+}
+
+/// How a launch ended
+pub(crate) struct Outcome {
+    pub(crate) statuses: Statuses,
+    /// First failure among the steps, success if none failed
+    pub(crate) srun: ExitStatus,
+    /// Why lmap stopped the jobs before they ended by themselves
+    pub(crate) stopped: Option<String>,
+}
+
+/// Watches srun steps, new steps can be added while others run.
+///
+/// srun errors are forwarded while collecting the tasks they report as failed.
+/// Every job is stopped when a rank of an abort job fails or when lmap gets
+/// SIGINT or SIGTERM: srun forwards SIGTERM to the tasks, steps are cancelled
+/// if they are still running after the grace period and srun is killed as a
+/// last resort
+pub(crate) struct Supervisor {
+    steps: Vec<Step>,
+    /// Exit status of each ended step
+    ended: Vec<Option<ExitStatus>>,
+    /// Lines of the error output of each step, None once it is closed
+    tx: Sender<(usize, Option<String>)>,
+    rx: Receiver<(usize, Option<String>)>,
+    grace: Duration,
+    margin: Duration,
+    statuses: Statuses,
+    stopped: Option<String>,
+    /* Once stopping, when to escalate */
+    deadline: Option<Instant>,
+    cancelled: bool,
+}
+
+impl Supervisor {
+    pub(crate) fn new(launcher: &Launcher, grace: Duration) -> Supervisor {
+        let (tx, rx) = mpsc::channel();
+        Supervisor {
+            steps: Vec::new(),
+            ended: Vec::new(),
+            tx,
+            rx,
+            grace,
+            margin: launcher.margin,
+            statuses: Statuses::new(),
+            stopped: None,
+            deadline: None,
+            cancelled: false,
+        }
+    }
+
+    pub(crate) fn add(&mut self, mut step: Step) -> Result<()> {
+        let stderr = step
+            .srun
+            .stderr
+            .take()
+            .ok_or(anyhow!("The error output of srun is not piped"))?;
+
+        let i = self.steps.len();
+        let tx = self.tx.clone();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(|l| l.ok()) {
+                if tx.send((i, Some(line))).is_err() {
+                    return;
+                }
+            }
+            let _ = tx.send((i, None));
+        });
+
+        self.steps.push(step);
+        self.ended.push(None);
+
+        /* Late steps share the fate of the others */
+        if self.stopped.is_some() {
+            send_signal(self.steps[i].srun.id() as i32, libc::SIGTERM)?;
+        }
+
+        Ok(())
+    }
+
+    /// Is lmap stopping the jobs
+    pub(crate) fn stopping(&self) -> bool {
+        self.stopped.is_some()
+    }
+
+    /// Steps which did not end, already exited ones are not reaped yet and can
+    /// still be signaled
+    fn signal_running(&mut self, sig: i32) -> Result<()> {
+        for (step, ended) in self.steps.iter().zip(self.ended.iter()) {
+            if ended.is_none() {
+                send_signal(step.srun.id() as i32, sig)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self, why: String) -> Result<()> {
+        self.signal_running(libc::SIGTERM)?;
+        self.stopped = Some(why);
+        self.deadline = Some(Instant::now() + self.grace + self.margin);
+        Ok(())
+    }
+
+    fn on_line(&mut self, i: usize, line: &str) -> Result<()> {
+        eprintln!("{}", line);
+
+        for mut s in TaskStatus::parse_srun(line) {
+            let rank = match self.steps[i].rank(s.task) {
+                Some(r) => r,
+                None => continue,
+            };
+            s.job = rank.job.clone();
+            s.task = rank.task;
+
+            if rank.on_failure == Some(OnFailure::Abort) && self.stopped.is_none() && s.failed() {
+                eprintln!(
+                    "lmap: {} rank {} failed with {}, aborting every job",
+                    rank.job, rank.rank, s.reason
+                );
+                let why = format!("aborted after {} failed", rank.job);
+                self.stop(why)?;
+            }
+
+            self.statuses.insert(s.key(), s);
+        }
+
+        Ok(())
+    }
+
+    /// Wait for a step to end and give its job (None for a multi-prog step)
+    /// with the exit status of srun, returns Ok(None) once every step ended
+    pub(crate) fn next_ended(&mut self) -> Result<Option<(Option<String>, ExitStatus)>> {
+        while self.ended.iter().any(|e| e.is_none()) {
+            match self.rx.recv_timeout(Duration::from_millis(100)) {
+                Ok((i, Some(line))) => self.on_line(i, &line)?,
+                Ok((i, None)) => {
+                    let status = self.steps[i].srun.wait()?;
+                    self.ended[i] = Some(status);
+                    return Ok(Some((self.steps[i].job.clone(), status)));
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(anyhow!("Lost the error output of srun"))
+                }
+            }
+
+            if let Some(sig) = take_signal() {
+                if self.stopped.is_none() {
+                    eprintln!("lmap: received signal {}, stopping every job", sig);
+                    self.stop(format!("stopped by signal {}", sig))?;
+                } else {
+                    /* Asked twice, do not wait for the grace period */
+                    self.deadline = Some(Instant::now());
+                }
+            }
+
+            if self.deadline.is_some_and(|d| Instant::now() >= d) {
+                if !self.cancelled {
+                    eprintln!("lmap: jobs are still running, cancelling the steps");
+                    /* Two interrupts in a row make srun cancel its step */
+                    self.signal_running(libc::SIGINT)?;
+                    self.signal_running(libc::SIGINT)?;
+                    self.cancelled = true;
+                    self.deadline = Some(Instant::now() + self.margin);
+                } else {
+                    eprintln!("lmap: srun did not exit, killing it");
+                    self.signal_running(libc::SIGKILL)?;
+                    self.deadline = None;
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Wait for every step
+    pub(crate) fn finish(mut self) -> Result<Outcome> {
+        while self.next_ended()?.is_some() {}
+
+        let srun = self
+            .ended
+            .iter()
+            .flatten()
+            .find(|s| !s.success())
+            .or(self.ended.iter().flatten().next())
+            .copied()
+            .ok_or(anyhow!("No srun step was launched"))?;
+
+        Ok(Outcome {
+            statuses: self.statuses,
+            srun,
+            stopped: self.stopped,
+        })
+    }
+}
+
+impl Outcome {
+    /// Report how the ranks of the plan ended, statuses saved by the exec
+    /// wrapper in the directory win over what srun reported
+    pub(crate) fn report(self, plan: &Plan, status_dir: &Path, wrapped: bool) -> Result<()> {
+        /* The wrapper knows better than srun */
+        let mut statuses = self.statuses;
+        statuses.extend(TaskStatus::load_dir(status_dir));
+
+        crate::status::report(plan, &statuses, wrapped, self.srun, self.stopped.as_deref())
+    }
+}
+
+/// Supervise steps until they all end, see Supervisor
+pub(crate) fn supervise(launcher: &Launcher, steps: Vec<Step>, grace: Duration) -> Result<Outcome> {
+    let mut supervisor = Supervisor::new(launcher, grace);
+    for step in steps {
+        supervisor.add(step)?;
+    }
+    supervisor.finish()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::process::ExitStatusExt;
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+
+    /// Tests relying on the signals received by the process run one at a time
+    pub(crate) static SIGNALS: Mutex<()> = Mutex::new(());
+
+    /// Send a signal to the test process once the test is waiting for it
+    pub(crate) fn signal_later(sig: i32, after: Duration) {
+        std::thread::spawn(move || {
+            std::thread::sleep(after);
+            send_signal(std::process::id() as i32, sig).unwrap();
+        });
+    }
+
+    /// Directory with an srun script logging the signals it gets and ignoring
+    /// them, and a launcher running it with a short stop margin
+    fn fake_srun() -> (PathBuf, Launcher) {
+        let dir = std::env::temp_dir().join(format!("lmap-srun-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let srun = dir.join("srun");
+        std::fs::write(
+            &srun,
+            format!(
+                "#!/bin/sh\n\
+                 trap 'echo TERM >> {0}' TERM\n\
+                 trap 'echo INT >> {0}' INT\n\
+                 while :; do sleep 0.1; done\n",
+                dir.join("signals").display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&srun, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let launcher = Launcher {
+            program: srun.display().to_string(),
+            margin: Duration::from_millis(200),
+            dir: dir.clone(),
+        };
+
+        (dir, launcher)
+    }
+
+    #[test]
+    fn stop_escalates_to_cancel_and_kill() {
+        let _lock = SIGNALS.lock().unwrap_or_else(|e| e.into_inner());
+        let (dir, launcher) = fake_srun();
+        catch_signals(&[libc::SIGTERM]);
+
+        let start = Instant::now();
+        let step = Step::multi_prog(&launcher, Path::new("unused.conf"), &Plan::default()).unwrap();
+        signal_later(libc::SIGTERM, Duration::from_millis(300));
+        let outcome = supervise(&launcher, vec![step], Duration::ZERO).unwrap();
+
+        assert_eq!(outcome.stopped.as_deref(), Some("stopped by signal 15"));
+        /* srun ignored everything until it got killed */
+        assert_eq!(outcome.srun.signal(), Some(libc::SIGKILL));
+        assert!(2 * launcher.margin <= start.elapsed());
+
+        let signals = std::fs::read_to_string(dir.join("signals")).unwrap();
+        let signals: Vec<&str> = signals.lines().collect();
+        assert_eq!(signals.first(), Some(&"TERM"));
+        assert!(signals[1..].iter().all(|s| *s == "INT") && 1 < signals.len());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
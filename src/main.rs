@@ -1,10 +1,10 @@
-use std::ops::Deref;
+use std::collections::HashMap;
 use std::path::PathBuf;
 use std::str::FromStr;
 
 use anyhow::anyhow;
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use hwlocality::bitmap::BitmapRef;
 use hwlocality::cpu::cpuset::CpuSet;
 use hwlocality::object::types::ObjectType;
@@ -12,11 +12,81 @@ use hwlocality::{topology::builder::BuildFlags, Topology};
 use std::process::{Command, Stdio};
 use which::which;
 
+mod dynamic;
+mod exec;
+mod farm;
 mod joblist;
+mod launch;
 mod map;
+mod status;
+mod verify;
 use joblist::JobList;
+use joblist::Policy;
 use map::JobDesc;
+use map::NodeFeatures;
 use map::ProcMap;
+use status::TaskStatus;
+
+/// Value of the first line starting with key in a /proc file
+fn proc_value(file: &str, key: &str) -> Option<String> {
+    std::fs::read_to_string(file)
+        .ok()?
+        .lines()
+        .find(|l| l.starts_with(key))
+        .and_then(|l| l.split_once(':'))
+        .map(|(_, v)| v.trim().to_string())
+}
+
+/// Slurm features of a node, empty outside of Slurm
+fn slurm_features(host: &str) -> Vec<String> {
+    if which("scontrol").is_err() {
+        return Vec::new();
+    }
+
+    /* Slurm node names may differ from host names */
+    let node = std::env::var("SLURMD_NODENAME").unwrap_or(host.to_string());
+
+    let output = match Command::new("scontrol")
+        .args(["-o", "show", "node", &node])
+        .output()
+    {
+        Ok(o) => String::from_utf8_lossy(&o.stdout).to_string(),
+        Err(_) => return Vec::new(),
+    };
+
+    output
+        .split_whitespace()
+        .find_map(|kv| kv.strip_prefix("AvailableFeatures="))
+        .filter(|f| *f != "(null)")
+        .map(|f| f.split(',').map(|v| v.to_string()).collect())
+        .unwrap_or_default()
+}
+
+fn node_features(host: &str) -> Result<NodeFeatures> {
+    /* The main topology is restricted to this process, count on the whole node */
+    let topology = Topology::new()?;
+
+    let memory = proc_value("/proc/meminfo", "MemTotal")
+        .and_then(|v| v.trim_end_matches("kB").trim().parse::<u64>().ok())
+        .map(|kb| kb * 1024);
+
+    Ok(NodeFeatures {
+        cpu_model: proc_value("/proc/cpuinfo", "model name"),
+        memory,
+        cores: Some(topology.objects_with_type(ObjectType::Core).count()),
+        tags: slurm_features(host),
+    })
+}
+
+/// Host name as given by HOST, falls back to the system one
+pub(crate) fn current_host() -> Result<String> {
+    Ok(match std::env::var("HOST") {
+        Ok(val) => val,
+        Err(_) => hostname::get()?
+            .into_string()
+            .unwrap_or("unknown".to_string()),
+    })
+}
 
 fn output_map() -> Result<()> {
     let topology: Topology = Topology::builder()
@@ -27,10 +97,7 @@ fn output_map() -> Result<()> {
 
     let numa: Vec<usize> = topology
         .objects_with_type(ObjectType::NUMANode)
-        .map(|n| {
-            per_numa_cpuset.push(n.cpuset());
-            n
-        })
+        .inspect(|n| per_numa_cpuset.push(n.cpuset()))
         .filter(|n| !n.cpuset().unwrap().is_empty())
         .filter_map(|n| n.os_index())
         .collect();
@@ -47,19 +114,43 @@ fn output_map() -> Result<()> {
         })
         .collect::<Vec<_>>();
 
-    let host = match std::env::var("HOST") {
-        Ok(val) => val.parse().unwrap(),
-        Err(_) => hostname::get()?
-            .into_string()
-            .unwrap_or("unknown".to_string()),
-    };
+    /* Core holding each PU, tells hardware threads of the same core apart.
+    Cores are named by their first PU on the whole node: logical indexes are
+    renumbered in the restricted topology and differ from task to task */
+    let node = Topology::new()?;
+    let mut pu_core: HashMap<usize, usize> = HashMap::new();
+    for core in node.objects_with_type(ObjectType::Core) {
+        if let Some(set) = core.cpuset() {
+            let pus: Vec<usize> = set
+                .iter_set()
+                .map(|p| usize::try_from(p).unwrap())
+                .collect();
+            if let Some(first) = pus.iter().min().copied() {
+                for p in pus {
+                    pu_core.insert(p, first);
+                }
+            }
+        }
+    }
 
-    let rank: i32 = match std::env::var("PMI_RANK") {
-        Ok(val) => val.parse().unwrap(),
-        Err(_) => match std::env::var("PMIX_RANK") {
-            Ok(val) => val.parse().unwrap(),
-            Err(_) => -1,
-        },
+    let core = pu
+        .iter()
+        .map(|pus| {
+            /* A PU without core is a core of its own */
+            pus.iter()
+                .map(|p| pu_core.get(p).copied().unwrap_or(usize::MAX - *p))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    let host = current_host()?;
+
+    let rank: i32 = exec::launcher_rank().unwrap_or(-1);
+
+    /* Features are the same for the whole node, only the first task reports them */
+    let features = match std::env::var("SLURM_LOCALID").as_deref() {
+        Ok("0") | Err(_) => node_features(&host)?,
+        Ok(_) => NodeFeatures::default(),
     };
 
     println!(
@@ -68,7 +159,9 @@ fn output_map() -> Result<()> {
             host,
             rank: rank as u32,
             numa,
-            pu
+            pu,
+            core,
+            features
         })
         .unwrap()
     );
@@ -76,19 +169,97 @@ fn output_map() -> Result<()> {
     Ok(())
 }
 
+#[derive(Debug, Subcommand)]
+enum Action {
+    /// Bind the current task as the plan says and run the command
+    Exec {
+        #[clap(long)]
+        /// Plan written by lmap when mapping jobs (mapping.json)
+        plan: PathBuf,
+        #[clap(long)]
+        /// Run the command as a child and save its exit status in this directory
+        status: Option<PathBuf>,
+        #[clap(long)]
+        /// Job of the task when it runs in a step of its own, the launcher rank is then its rank in the job
+        job: Option<String>,
+        #[clap(last = true)]
+        /// Command to run, defaults to the command of the job of the task
+        command: Vec<String>,
+    },
+    /// Map the jobs and check that every rank gets bound as planned
+    Verify { job: PathBuf },
+    /// Run the tasks of a farm job, started by lmap on each of its ranks
+    #[command(hide = true)]
+    Farm {
+        #[clap(long)]
+        /// State directory of the farm holding its tasks and their claims
+        dir: PathBuf,
+        #[clap(long)]
+        /// Ranks running tasks, the others stay idle
+        concurrent: Option<usize>,
+        #[clap(long, default_value_t = 0)]
+        /// Times a failed task is run again
+        retries: usize,
+        #[clap(long)]
+        /// Log file template of each task
+        log: Option<String>,
+    },
+    /// Print the actual binding of the current task
+    #[command(hide = true)]
+    Probe,
+}
+
 #[derive(Debug, Parser)]
 struct Args {
+    #[command(subcommand)]
+    action: Option<Action>,
     #[clap(long, short, action)]
     /// Output mapping information for current process
     map: bool,
     #[clap(long, short, action)]
     /// Output mapping information for current process
     display: bool,
+    #[clap(long, short, value_enum, global = true)]
+    /// Default placement policy for jobs which do not define one
+    policy: Option<Policy>,
+    #[clap(long, short, action, global = true)]
+    /// Log each allocation decision and print the resulting occupancy per NUMA
+    explain: bool,
+    #[clap(long, short, global = true)]
+    /// Load the topology from a file containing the output of "lmap -m" instead of running discovery
+    topology: Option<PathBuf>,
+    #[clap(long, short = 'x', action, global = true)]
+    /// Launch ranks through "lmap exec" which binds them and sets their environment
+    exec: bool,
+    #[clap(long, short, action)]
+    /// Launch each job as its own srun step instead of a single --multi-prog step (implies -x)
+    steps: bool,
+    #[clap(long, action)]
+    /// Launch jobs as soon as the jobs they come after ended and free slots allow, growing elastic jobs into freed slots (implies -s)
+    dynamic: bool,
     job: Option<PathBuf>,
 }
 
 fn main() -> Result<()> {
-    let args = Args::parse();
+    let mut args = Args::parse();
+
+    let verify = match args.action.take() {
+        Some(Action::Exec {
+            plan,
+            status,
+            job,
+            command,
+        }) => return exec::run(plan, status, job, command),
+        Some(Action::Probe) => return verify::probe(),
+        Some(Action::Farm {
+            dir,
+            concurrent,
+            retries,
+            log,
+        }) => return farm::work(dir, concurrent, retries, log),
+        Some(Action::Verify { job }) => Some(job),
+        None => None,
+    };
 
     // Bypass for use in discovery
     if args.map {
@@ -106,38 +277,157 @@ fn main() -> Result<()> {
         return Err(anyhow!(e));
     }
 
-    let mut pmap = ProcMap::init()?;
+    let mut pmap = ProcMap::init(args.topology.clone())?;
 
-    if args.job.is_none() {
-        if args.display {
-            println!("{}", pmap);
-            pmap.display();
+    let job = match verify.clone().or(args.job.clone()) {
+        Some(j) => j,
+        None => {
+            if args.display {
+                println!("{}", pmap);
+                pmap.display(None);
+                return Ok(());
+            }
+
+            println!("Pass a job.yml file to run a job");
             return Ok(());
         }
+    };
+
+    let mut jobs = JobList::load(job)?;
 
-        println!("Pass a job.yml file to run a job");
-        return Ok(());
+    if let Some(policy) = args.policy {
+        jobs.set_default_policy(policy);
     }
 
-    let mut jobs = JobList::load(args.job.unwrap())?;
+    /* Verification probes replace the commands anyway */
+    if verify.is_none() {
+        farm::prepare(&mut jobs)?;
+    }
 
     println!("{:?}", jobs);
 
-    pmap.map(&mut jobs)?;
+    pmap.set_explain(args.explain);
+
+    let stages = jobs.into_stages()?;
 
-    if args.display {
-        println!("{}", pmap);
-        pmap.display();
+    let launcher = launch::Launcher::new(std::env::current_dir()?);
+
+    if args.dynamic && verify.is_none() {
+        launch::catch_signals(&[libc::SIGINT, libc::SIGTERM]);
+        return dynamic::run(&launcher, args.display, pmap, stages);
+    }
+
+    /* Map every stage before launching anything, each one gets the whole allocation */
+    let mut mapped: Vec<(ProcMap, JobList)> = Vec::new();
+    for mut stage in stages {
+        let mut stage_map = pmap.clone();
+        stage_map.map(&mut stage)?;
+
+        if args.display {
+            println!("{}", stage_map);
+            stage_map.display(Some(&stage));
+        }
+
+        mapped.push((stage_map, stage));
     }
 
-    pmap.to_slurm(PathBuf::from_str("./jobfile.slurm")?, &jobs)?;
+    /* Stop requests are forwarded to srun, see launch::supervise */
+    launch::catch_signals(&[libc::SIGINT, libc::SIGTERM]);
+
+    let count = mapped.len();
+    for (i, (stage_map, stage)) in mapped.into_iter().enumerate() {
+        if count > 1 {
+            println!(
+                "Stage {}/{}: {}",
+                i + 1,
+                count,
+                stage
+                    .iter()
+                    .map(|j| j.label())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
 
-    // Create a new command with "ls" as the executable
-    let mut cmd = Command::new("srun")
-        .arg("--multi-prog")
-        .arg("./jobfile.slurm")
-        .spawn()?;
-    cmd.wait()?;
+        /* Later stages depend on this one */
+        if let Err(e) = run_stage(&args, &launcher, &stage_map, stage, verify.is_some()) {
+            if i + 1 != count {
+                eprintln!(
+                    "lmap: stage {} did not complete, skipping the {} later stage(s)",
+                    i + 1,
+                    count - i - 1
+                );
+            }
+            return Err(e);
+        }
+    }
 
     Ok(())
 }
+
+/// Launch the jobs of a stage, or check their binding, once mapped
+fn run_stage(
+    args: &Args,
+    launcher: &launch::Launcher,
+    pmap: &ProcMap,
+    mut jobs: JobList,
+    verify: bool,
+) -> Result<()> {
+    let plan = launcher.dir.join("mapping.json");
+    let mapping = pmap.to_plan(&jobs);
+    mapping.save(plan.clone())?;
+
+    /* The wrapper saves the exit status of each task there, wrapped probes
+    as well */
+    let status_dir = TaskStatus::reset_dir(&launcher.dir)?;
+
+    /* Only the wrapper can run a failed rank again or stop it gracefully */
+    let wrapped = args.exec || args.steps || jobs.iter().any(|j| j.needs_wrapper());
+
+    let wrapper_args = exec::wrapper_args(&plan, &status_dir)?;
+
+    let wrapper = if wrapped {
+        Some(format!("{} --", wrapper_args.join(" ")))
+    } else {
+        None
+    };
+
+    if verify {
+        /* Same launch as the jobs with a probe instead of their command */
+        jobs.set_command(vec![
+            std::env::current_exe()?.display().to_string(),
+            "probe".to_string(),
+        ]);
+        pmap.to_slurm(
+            PathBuf::from_str("./verify.slurm")?,
+            &jobs,
+            wrapper.as_deref(),
+        )?;
+
+        let output = Command::new("srun")
+            .arg("--multi-prog")
+            .arg("./verify.slurm")
+            .stderr(Stdio::inherit())
+            .output()?;
+
+        return verify::check(
+            &pmap.to_plan(&jobs),
+            &String::from_utf8_lossy(&output.stdout),
+            wrapped,
+        );
+    }
+
+    let steps = if args.steps {
+        mapping
+            .jobs()
+            .into_iter()
+            .map(|j| launch::Step::job(launcher, &mapping, j, &wrapper_args))
+            .collect::<Result<Vec<_>>>()?
+    } else {
+        let jobfile = PathBuf::from_str("./jobfile.slurm")?;
+        pmap.to_slurm(jobfile.clone(), &jobs, wrapper.as_deref())?;
+        vec![launch::Step::multi_prog(launcher, &jobfile, &mapping)?]
+    };
+
+    launch::supervise(launcher, steps, jobs.grace_period())?.report(&mapping, &status_dir, wrapped)
+}
@@ -2,12 +2,21 @@ use anyhow::anyhow;
 use anyhow::Result;
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::HashSet;
 use std::io::Write;
 use std::path::PathBuf;
 use std::process::Stdio;
 use std::{collections::HashMap, env, io::Read};
 use yansi::Paint;
 
+mod solver;
+
+use crate::exec::{Plan, RankPlan};
+use crate::joblist::Exclusive;
+use crate::joblist::JobEntry;
+use crate::joblist::Policy;
+use crate::joblist::Smt;
 use crate::JobList;
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -16,17 +25,83 @@ pub(crate) struct JobDesc {
     pub(crate) rank: u32,
     pub(crate) numa: Vec<usize>,
     pub(crate) pu: Vec<Vec<usize>>,
+    /// Core of each PU named by its first PU on the node, older discovery
+    /// outputs lack it
+    #[serde(default)]
+    pub(crate) core: Vec<Vec<usize>>,
+    /// Only reported by one task per node, older discovery outputs lack it
+    #[serde(default)]
+    pub(crate) features: NodeFeatures,
+}
+
+/// Properties of a node gathered at discovery
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub(crate) struct NodeFeatures {
+    pub(crate) cpu_model: Option<String>,
+    /// Total memory in bytes
+    pub(crate) memory: Option<u64>,
+    pub(crate) cores: Option<usize>,
+    /// Slurm features of the node (AvailableFeatures in scontrol)
+    #[serde(default)]
+    pub(crate) tags: Vec<String>,
+}
+
+impl NodeFeatures {
+    /// Complete what is unknown with what another task of the node reported
+    fn merge(&mut self, other: &NodeFeatures) {
+        if self.cpu_model.is_none() {
+            self.cpu_model = other.cpu_model.clone();
+        }
+        if self.memory.is_none() {
+            self.memory = other.memory;
+        }
+        if self.cores.is_none() {
+            self.cores = other.cores;
+        }
+        for t in other.tags.iter() {
+            if !self.tags.contains(t) {
+                self.tags.push(t.clone());
+            }
+        }
+    }
+
+    fn describe(&self) -> String {
+        let mut ret = Vec::new();
+
+        if let Some(cores) = self.cores {
+            ret.push(format!("{} cores", cores));
+        }
+        if let Some(mem) = self.memory {
+            ret.push(format!("{} GiB", mem >> 30));
+        }
+        if let Some(model) = self.cpu_model.as_ref() {
+            ret.push(model.clone());
+        }
+        if !self.tags.is_empty() {
+            ret.push(format!("features: {}", self.tags.join(",")));
+        }
+
+        if ret.is_empty() {
+            return "unknown".to_string();
+        }
+
+        ret.join(", ")
+    }
 }
 
 pub trait CountChild {
     fn count(&self) -> i32;
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Slot {
     rank: i32,
     pu: Vec<usize>,
+    /// Hardware thread index of each PU in its core, 0 for the primary one
+    smt: Vec<usize>,
     job: Option<u32>,
+    /// Kept idle for a job which is exclusive on the enclosing domain
+    reserved: Option<u32>,
 }
 
 impl std::fmt::Display for Slot {
@@ -63,8 +138,27 @@ impl Slot {
         }
     }
 
+    /// PUs of the slot a job uses given its SMT mode
+    fn pus(&self, smt: Smt) -> Vec<usize> {
+        self.pu
+            .iter()
+            .zip(self.smt.iter())
+            .filter(|(_, t)| match smt {
+                Smt::On => true,
+                Smt::Off => **t == 0,
+                Smt::Pair => **t != 0,
+            })
+            .map(|(p, _)| *p)
+            .collect()
+    }
+
     fn is_free(&self) -> bool {
-        self.job.is_none()
+        self.job.is_none() && self.reserved.is_none()
+    }
+
+    /// Is the slot used or reserved by the given job
+    fn held_by(&self, jobid: u32) -> bool {
+        self.job == Some(jobid) || self.reserved == Some(jobid)
     }
 
     fn acquire(&mut self, jobid: u32) -> Result<()> {
@@ -72,13 +166,17 @@ impl Slot {
             return Err(anyhow!("Job is already taken"));
         }
 
+        if self.reserved.is_some_and(|r| r != jobid) {
+            return Err(anyhow!("Slot is reserved by an exclusive job"));
+        }
+
         self.job = Some(jobid);
 
         Ok(())
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Numa {
     id: usize,
     slots: Vec<Slot>,
@@ -114,22 +212,70 @@ impl Numa {
         by_rank
     }
 
-    fn acquire(&mut self, jobid: u32) -> Result<()> {
-        for slot in self.slots.iter_mut() {
-            if slot.is_free() {
-                slot.acquire(jobid)?;
-                return Ok(());
+    /// First slots of the runs of adjacent free slots a rank of the job can
+    /// use, ranks of jobs with several cores per rank span such a run
+    fn free_ranks(&self, job: &JobEntry) -> Vec<usize> {
+        let width = job.cores_per_rank;
+        let mut ret = Vec::new();
+        let mut idx = 0;
+
+        while idx + width <= self.slots.len() {
+            match self.slots[idx..idx + width]
+                .iter()
+                .rposition(|s| !s.is_free() || s.pus(job.smt).is_empty())
+            {
+                /* Restart after the last taken slot of the window */
+                Some(taken) => idx += taken + 1,
+                None => {
+                    ret.push(idx);
+                    idx += width;
+                }
             }
         }
-        /* If we are here we found no slot */
-        Err(anyhow!("No free slot on numa {}", self.id))
+
+        ret
+    }
+}
+
+/// Location of a slot as (host, numa, index in numa)
+type SlotRef = (String, usize, usize);
+
+/// Location of a NUMA domain as (host, numa)
+type NumaRef = (String, usize);
+
+/// NUMA domains a job may use given its affinity constraints
+struct NumaFilter {
+    allow: Option<HashSet<NumaRef>>,
+    deny: HashSet<NumaRef>,
+}
+
+impl NumaFilter {
+    fn any() -> NumaFilter {
+        NumaFilter {
+            allow: None,
+            deny: HashSet::new(),
+        }
+    }
+
+    fn accepts(&self, host: &str, numa: usize) -> bool {
+        let key = (host.to_string(), numa);
+
+        if self.deny.contains(&key) {
+            return false;
+        }
+
+        match self.allow.as_ref() {
+            Some(allow) => allow.contains(&key),
+            None => true,
+        }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Node {
     host: String,
-    numas: HashMap<usize, Numa>,
+    numas: BTreeMap<usize, Numa>,
+    features: NodeFeatures,
 }
 
 impl CountChild for Node {
@@ -138,19 +284,6 @@ impl CountChild for Node {
     }
 }
 
-impl Node {
-    fn acquire(&mut self, jobid: u32) -> Result<()> {
-        for numa in self.numas.values_mut() {
-            if let Ok(()) = numa.acquire(jobid) {
-                /* Stop on first numa acquire */
-                return Ok(());
-            }
-        }
-        /* If we are here we found no slot */
-        Err(anyhow!("No free slot on node {}", self.host))
-    }
-}
-
 struct RandomColor {
     cols: Vec<(u8, u8, u8)>,
     cur: usize,
@@ -228,9 +361,21 @@ impl RandomColor {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct ProcMap {
-    nodes: HashMap<String, Node>,
+    nodes: BTreeMap<String, Node>,
+    /// Log allocation decisions (--explain)
+    explain: bool,
+    /// Previous (job, reserved) of modified slots, undone by rollback
+    journal: Vec<(SlotRef, Option<u32>, Option<u32>)>,
+    /// Number of checkpoints not yet committed or rolled back
+    open_checkpoints: usize,
+}
+
+/// Position in the allocation journal, see ProcMap::checkpoint
+#[must_use]
+pub(crate) struct Checkpoint {
+    mark: usize,
 }
 
 impl CountChild for ProcMap {
@@ -257,20 +402,46 @@ impl std::fmt::Display for ProcMap {
 }
 
 impl ProcMap {
-    pub(crate) fn init() -> Result<ProcMap> {
+    pub(crate) fn init(topology: Option<PathBuf>) -> Result<ProcMap> {
+        // Discover topology or load it from a previous discovery output
+        let jobs = match topology {
+            Some(file) => ProcMap::load_discovery(file)?,
+            None => ProcMap::discovery()?,
+        };
+
+        Ok(ProcMap::from_descs(&jobs))
+    }
+
+    pub(crate) fn from_descs(jobs: &[JobDesc]) -> ProcMap {
         let mut ret = ProcMap {
-            nodes: HashMap::new(),
+            nodes: BTreeMap::new(),
+            explain: false,
+            journal: Vec::new(),
+            open_checkpoints: 0,
         };
 
-        // Discover topology
-        let jobs = ProcMap::discovery()?;
+        /* PUs of each core of each node, the first one is the primary thread */
+        let mut cores: HashMap<(String, usize), Vec<usize>> = HashMap::new();
+        for job in jobs.iter() {
+            for (pus, core) in job.pu.iter().zip(job.core.iter()) {
+                for (pu, c) in pus.iter().zip(core.iter()) {
+                    cores.entry((job.host.clone(), *c)).or_default().push(*pu);
+                }
+            }
+        }
+        for pus in cores.values_mut() {
+            pus.sort();
+            pus.dedup();
+        }
 
         // Insert in internal state
         for job in jobs.iter() {
             let node = ret.nodes.entry(job.host.clone()).or_insert(Node {
                 host: job.host.clone(),
-                numas: HashMap::new(),
+                numas: BTreeMap::new(),
+                features: NodeFeatures::default(),
             });
+            node.features.merge(&job.features);
             for (cnt, numa_id) in job.numa.iter().enumerate() {
                 let numa = node.numas.entry(*numa_id).or_insert(Numa {
                     id: *numa_id,
@@ -279,61 +450,195 @@ impl ProcMap {
 
                 let slots = job.pu.get(cnt).expect("Failed to retrieve slots");
 
+                /* Without core information every PU is its own core */
+                let smt = match job.core.get(cnt) {
+                    Some(core) => slots
+                        .iter()
+                        .zip(core.iter())
+                        .map(|(pu, c)| {
+                            cores[&(job.host.clone(), *c)]
+                                .iter()
+                                .position(|p| p == pu)
+                                .unwrap_or(0)
+                        })
+                        .collect(),
+                    None => vec![0; slots.len()],
+                };
+
                 numa.slots.push(Slot {
                     rank: job.rank as i32,
                     pu: slots.clone(),
+                    smt,
                     job: None,
+                    reserved: None,
                 });
             }
         }
 
-        Ok(ret)
+        /* Discovery lines come in any order, ranks over several cores rely
+        on slots being adjacent */
+        for numa in ret.nodes.values_mut().flat_map(|n| n.numas.values_mut()) {
+            numa.slots.sort_by_key(|s| s.pu.first().copied());
+        }
+
+        ret
     }
 
-    pub(crate) fn to_slurm(&mut self, out: PathBuf, jobs: &JobList) -> Result<()> {
+    /// Write the multi-prog file, with a wrapper (lmap exec) ranks are bound
+    /// by the wrapper and every job fits on one line
+    pub(crate) fn to_slurm(
+        &self,
+        out: PathBuf,
+        jobs: &JobList,
+        wrapper: Option<&str>,
+    ) -> Result<()> {
         let mut per_job: HashMap<u32, Vec<i32>> = HashMap::new();
 
-        for slot in self.each_slot() {
-            if let Some(j) = slot.job {
-                let vec = per_job.entry(j).or_insert(Vec::new());
-                vec.push(slot.rank);
-            }
-        }
-
-        /* At this point for each job we have a rank list */
         let file = std::fs::File::create(out)?;
         let mut out = std::io::BufWriter::new(file);
 
-        for (k, v) in per_job.iter() {
-            let j = if let Some(j) = jobs.job_by_id(*k) {
-                j
+        for j in jobs.iter() {
+            let ranks = self.job_ranks(j);
+
+            if ranks.is_empty() {
+                continue;
+            }
+
+            /* The first task of a rank runs it, the others stay idle */
+            let v = per_job.entry(j.id).or_default();
+            v.extend(ranks.iter().map(|(_, r)| r[0].rank));
+
+            let line = if wrapper.is_some() || (j.threads().is_none() && j.smt == Smt::On) {
+                format!(
+                    "# {}\n{} {}{}\n",
+                    j.label(),
+                    v.iter()
+                        .map(|v| v.to_string())
+                        .collect::<Vec<_>>()
+                        .join(","),
+                    wrapper.map(|w| format!("{} ", w)).unwrap_or_default(),
+                    j.command.join(" ")
+                )
             } else {
-                return Err(anyhow!("No such job {}", k));
+                /* Each rank gets the PUs of its slots and a thread environment */
+                let mut line = format!("# {}\n", j.label());
+                for (_, r) in ranks.iter() {
+                    let pus = ProcMap::rank_pus(j, r);
+
+                    /* Slurm binds each task, wider ranks and SMT modes have to be rebound */
+                    let bind = if j.cores_per_rank != 1 || j.smt != Smt::On {
+                        format!(
+                            "taskset -c {} ",
+                            pus.iter()
+                                .map(|v| v.to_string())
+                                .collect::<Vec<_>>()
+                                .join(",")
+                        )
+                    } else {
+                        String::new()
+                    };
+
+                    line += &format!(
+                        "{} env {}{}{}\n",
+                        r[0].rank,
+                        j.thread_env(&pus)
+                            .iter()
+                            .map(|v| format!("{} ", v))
+                            .collect::<String>(),
+                        bind,
+                        j.command.join(" ")
+                    );
+                }
+                line
             };
 
+            out.write_all(line.as_bytes())?;
+        }
+
+        /* Multi-prog requires a program for every task, idle ones do nothing */
+        let mut idle: Vec<i32> = self
+            .slots()
+            .filter(|s| !per_job.values().any(|v| v.contains(&s.rank)))
+            .map(|s| s.rank)
+            .collect();
+        idle.sort();
+        idle.dedup();
+
+        if !idle.is_empty() {
             let line = format!(
-                "{} {}\n",
-                v.iter()
+                "{} true\n",
+                idle.iter()
                     .map(|v| v.to_string())
                     .collect::<Vec<_>>()
-                    .join(","),
-                j.command.join(" ")
+                    .join(",")
             );
-
             out.write_all(line.as_bytes())?;
         }
 
         Ok(())
     }
 
-    fn each_node(&mut self) -> impl Iterator<Item = &mut Node> {
-        self.nodes.iter_mut().map(|(_, node)| node)
+    /// Slots of each rank of a job, cores_per_rank adjacent slots per rank
+    fn job_ranks(&self, job: &JobEntry) -> Vec<(NumaRef, Vec<&Slot>)> {
+        self.nodes
+            .iter()
+            .flat_map(|(host, node)| node.numas.values().map(move |numa| (host, numa)))
+            .flat_map(|(host, numa)| {
+                numa.slots
+                    .iter()
+                    .filter(|s| s.job == Some(job.id))
+                    .collect::<Vec<_>>()
+                    .chunks(job.cores_per_rank)
+                    .map(|c| ((host.clone(), numa.id), c.to_vec()))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// PUs a rank of the job runs on
+    fn rank_pus(job: &JobEntry, slots: &[&Slot]) -> Vec<usize> {
+        let mut pus: Vec<usize> = slots.iter().flat_map(|s| s.pus(job.smt)).collect();
+        pus.sort();
+        pus.dedup();
+        pus
     }
 
-    fn each_numa(&mut self) -> impl Iterator<Item = &mut Numa> {
-        self.nodes
-            .values_mut()
-            .flat_map(|node| node.numas.values_mut())
+    /// Per rank binding and environment of every job, for lmap exec
+    pub(crate) fn to_plan(&self, jobs: &JobList) -> Plan {
+        let mut plan = Plan::default();
+
+        for j in jobs.iter() {
+            let ranks = self.job_ranks(j);
+            let size = ranks.len();
+
+            for (rank, ((host, numa), slots)) in ranks.iter().enumerate() {
+                let pus = ProcMap::rank_pus(j, slots);
+
+                let mut env = j.thread_env(&pus);
+                env.push(format!("LMAP_JOB={}", j.label()));
+                env.push(format!("LMAP_JOB_RANK={}", rank));
+                env.push(format!("LMAP_JOB_SIZE={}", size));
+
+                plan.ranks.push(RankPlan {
+                    task: slots[0].rank,
+                    host: host.clone(),
+                    job: j.label(),
+                    rank,
+                    size,
+                    pus,
+                    numas: vec![*numa],
+                    env,
+                    command: j.command.clone(),
+                    output: j.output.clone(),
+                    on_failure: j.on_failure,
+                    stop_signal: j.stop_signal,
+                    grace_period: j.grace_period,
+                    timeout: j.timeout,
+                });
+            }
+        }
+
+        plan
     }
 
     fn each_slot(&mut self) -> impl Iterator<Item = &mut Slot> {
@@ -344,271 +649,1104 @@ impl ProcMap {
         })
     }
 
-    fn count_free_slots(&mut self) -> usize {
+    fn slots(&self) -> impl Iterator<Item = &Slot> {
+        self.nodes
+            .values()
+            .flat_map(|node| node.numas.values().flat_map(|numa| numa.slots.iter()))
+    }
+
+    pub(crate) fn count_free_slots(&mut self) -> usize {
         self.each_slot().filter(|v| v.is_free()).count()
     }
 
-    fn map_for_defined_size(
-        &mut self,
-        level: &str,
-        size: usize,
-        jobid: u32,
-        on_each: bool,
-    ) -> Result<()> {
-        let mut number_to_alloc = size;
-
-        match level {
-            "numa" => {
-                while 0 < number_to_alloc {
-                    let mut did_alloc = false;
-                    for nu in self.each_numa() {
-                        match nu.acquire(jobid) {
-                            Ok(_) => {
-                                did_alloc = true;
-                                number_to_alloc -= 1;
-                            }
-                            Err(e) => {
-                                if on_each {
-                                    return Err(anyhow!("No room on Numa"));
-                                }
-                                continue;
-                            }
-                        }
-                        if number_to_alloc == 0 {
-                            break;
-                        }
-                    }
+    /// Free ranks of a job grouped by node and then by NUMA, each rank is
+    /// given by its first slot
+    fn free_slots(&self, filter: &NumaFilter, job: &JobEntry) -> Vec<Vec<Vec<SlotRef>>> {
+        self.nodes
+            .iter()
+            .map(|(host, node)| {
+                node.numas
+                    .iter()
+                    .filter(|(numa_id, _)| filter.accepts(host, **numa_id))
+                    .map(|(numa_id, numa)| {
+                        numa.free_ranks(job)
+                            .into_iter()
+                            .map(|idx| (host.clone(), *numa_id, idx))
+                            .collect()
+                    })
+                    .collect()
+            })
+            .collect()
+    }
 
-                    if did_alloc == false {
-                        return Err(anyhow!(
-                            "No room to alocate fixed on NUMA ({} left)",
-                            number_to_alloc
-                        ));
-                    }
-                }
-            }
-            "node" => {
-                while 0 < number_to_alloc {
-                    let mut did_alloc = false;
-                    for n in self.each_node() {
-                        match n.acquire(jobid) {
-                            Ok(_) => {
-                                did_alloc = true;
-                                number_to_alloc -= 1;
-                            }
-                            Err(e) => {
-                                if on_each {
-                                    return Err(anyhow!("No room on Node"));
-                                }
-                                continue;
-                            }
-                        }
-                        if number_to_alloc == 0 {
-                            break;
-                        }
-                    }
+    fn slot_mut(&mut self, slot: &SlotRef) -> Option<&mut Slot> {
+        self.nodes
+            .get_mut(&slot.0)
+            .and_then(|node| node.numas.get_mut(&slot.1))
+            .and_then(|numa| numa.slots.get_mut(slot.2))
+    }
 
-                    if did_alloc == false {
-                        return Err(anyhow!(
-                            "No room to alocate fixed on NODE ({} left)",
-                            number_to_alloc
-                        ));
-                    }
-                }
-            }
-            "slot" => {
-                for s in self.each_slot() {
-                    if s.is_free() {
-                        s.acquire(jobid)?;
-                        number_to_alloc -= 1;
-                    }
+    /// Start a transaction: slot changes made after this point can be undone
+    /// with rollback() or kept with commit(), checkpoints can be nested
+    pub(crate) fn checkpoint(&mut self) -> Checkpoint {
+        self.open_checkpoints += 1;
+        Checkpoint {
+            mark: self.journal.len(),
+        }
+    }
 
-                    if number_to_alloc == 0 {
-                        break;
-                    }
-                }
+    /// Keep changes since the checkpoint, an enclosing checkpoint can still undo them
+    pub(crate) fn commit(&mut self, _cp: Checkpoint) {
+        self.open_checkpoints -= 1;
+        if self.open_checkpoints == 0 {
+            /* Not journal.clear() which resolves to yansi::Paint::clear */
+            Vec::clear(&mut self.journal);
+        }
+    }
 
-                if number_to_alloc != 0 {
-                    /* Not enough slots */
-                    return Err(anyhow!(
-                        "Not enough slots available to allocate {} slots",
-                        size
-                    ));
-                }
+    /// Undo every slot change made since the checkpoint
+    pub(crate) fn rollback(&mut self, cp: Checkpoint) {
+        self.open_checkpoints -= 1;
+        while cp.mark < self.journal.len() {
+            let (slot, job, reserved) = self.journal.pop().unwrap();
+            if let Some(s) = self.slot_mut(&slot) {
+                s.job = job;
+                s.reserved = reserved;
             }
-            _ => {
-                return Err(anyhow!("No such locality specifier {}", level));
-            }
-        };
+        }
+    }
 
-        Ok(())
+    /// Keep the previous state of a slot for rollback, only needed while a
+    /// checkpoint is open
+    fn record(&mut self, slot: &SlotRef, job: Option<u32>, reserved: Option<u32>) {
+        if 0 < self.open_checkpoints {
+            self.journal.push((slot.clone(), job, reserved));
+        }
     }
 
-    pub(crate) fn map(&mut self, jobs: &mut JobList) -> Result<()> {
-        /* We start by mapping "for each" jobs */
-        for j in jobs.each_jobs() {
-            if let Some(loc) = j.loc.as_ref() {
-                match loc.as_str() {
-                    "numa" => {
-                        for nu in self.each_numa() {
-                            nu.acquire(jobs.job_id(j)?)?;
-                        }
-                    }
-                    "node" => {
-                        for n in self.each_node() {
-                            n.acquire(jobs.job_id(j)?)?;
-                        }
-                    }
-                    "slot" => {
-                        for s in self.each_slot() {
-                            s.acquire(jobs.job_id(j)?)?;
-                        }
-                    }
-                    _ => {
-                        return Err(anyhow!("No such locality specifier {}", loc));
+    /// Free the slots used or reserved by a job which ended, returns how many
+    pub(crate) fn release(&mut self, jobid: u32) -> usize {
+        let mut held: Vec<SlotRef> = Vec::new();
+        for (host, node) in self.nodes.iter() {
+            for (id, numa) in node.numas.iter() {
+                for (idx, s) in numa.slots.iter().enumerate() {
+                    if s.held_by(jobid) {
+                        held.push((host.clone(), *id, idx));
                     }
-                };
-            } else {
-                unreachable!("All Each specifier has to provide a locality");
+                }
             }
         }
 
-        /* Now we map fixed JOBs */
-        for j in jobs.fixed_jobs() {
-            let number_to_alloc = match j.order.parse::<usize>() {
-                Ok(num) => num,
-                Err(e) => {
-                    return Err(anyhow!(
-                        "Failed to parse value for fixed alloc {} : {}",
-                        j.order,
-                        e
-                    ));
-                }
-            };
-
-            /* Now we want to acquire as many as per fixed using the correct walk logic */
-            self.map_for_defined_size(&j.loc_or_slot(), number_to_alloc, jobs.job_id(j)?, true)?;
+        for slot in held.iter() {
+            if let Some(s) = self.slot_mut(slot) {
+                let previous = (s.job, s.reserved);
+                s.job = None;
+                s.reserved = None;
+                self.record(slot, previous.0, previous.1);
+            }
         }
 
-        /* Eventually we map the "all" jobs */
-        let remaining_slots = self.count_free_slots();
-        let all_job_count = jobs.all_jobs_count();
-        let quantum = remaining_slots / all_job_count;
-        let rest = remaining_slots - (all_job_count * quantum);
-
-        let mut is_first = true;
+        held.len()
+    }
 
-        for j in jobs.all_jobs() {
-            /* We give the rest to the first job */
-            let tsize = if is_first {
-                is_first = false;
-                quantum + rest
-            } else {
-                quantum
-            };
+    /// Slot changes go through acquire_slot, reserve_slot or release to be
+    /// journaled
+    fn acquire_slot(&mut self, slot: &SlotRef, jobid: u32) -> Result<()> {
+        let s = match self.slot_mut(slot) {
+            Some(s) => s,
+            None => return Err(anyhow!("No such slot {:?}", slot)),
+        };
 
-            self.map_for_defined_size(&j.loc_or_slot(), tsize, jobs.job_id(j)?, false)?;
-        }
+        let previous = (s.job, s.reserved);
+        s.acquire(jobid)?;
+        self.record(slot, previous.0, previous.1);
 
         Ok(())
     }
 
-    fn print_block_color(names: Vec<String>, len: usize, col: (u8, u8, u8), multiplier: u8) {
-        if len == 0 {
-            return;
+    /// Acquire the slots of a rank starting at the given slot
+    fn acquire_rank(&mut self, first: &SlotRef, job: &JobEntry) -> Result<()> {
+        for i in 0..job.cores_per_rank {
+            self.acquire_slot(&(first.0.clone(), first.1, first.2 + i), job.id)?;
         }
 
-        let len = len * multiplier as usize;
+        Ok(())
+    }
 
-        let mut text: Option<String> = None;
+    fn reserve_slot(&mut self, slot: &SlotRef, jobid: u32) {
+        if let Some(s) = self.slot_mut(slot) {
+            let previous = (s.job, s.reserved);
+            s.reserved = Some(jobid);
+            self.record(slot, previous.0, previous.1);
+        }
+    }
 
-        for s in names {
-            if s.len() < len {
-                text = Some(s);
+    /// Take one element from each list in turn until all are empty
+    fn interleave(domains: Vec<Vec<SlotRef>>) -> Vec<SlotRef> {
+        let mut ret = Vec::new();
+        let mut iters: Vec<_> = domains.into_iter().map(|v| v.into_iter()).collect();
+
+        loop {
+            let mut did_take = false;
+            for it in iters.iter_mut() {
+                if let Some(s) = it.next() {
+                    ret.push(s);
+                    did_take = true;
+                }
+            }
+            if !did_take {
                 break;
             }
         }
 
-        let mut left = len;
+        ret
+    }
 
-        if let Some(s) = text {
-            print!("{}", s.black().on_rgb(col.0, col.1, col.2));
-            left -= s.len();
+    /// Give contiguous chunks of ceil(size / domains) slots to each domain in
+    /// turn, if domains are too small what is left goes to the first free slots
+    fn blocks(domains: Vec<Vec<SlotRef>>, size: usize) -> Vec<SlotRef> {
+        if domains.is_empty() {
+            return Vec::new();
         }
 
-        for _ in 0..left {
-            print!("{}", " ".on_rgb(col.0, col.1, col.2));
+        let chunk = size.div_ceil(domains.len());
+        let mut ret = Vec::new();
+        let mut spare = Vec::new();
+
+        for d in domains {
+            let take = chunk.min(size - ret.len());
+            let mut d = d.into_iter();
+            ret.extend(d.by_ref().take(take));
+            spare.extend(d);
         }
-    }
 
-    fn print_block(names: Vec<String>, len: usize, col: &mut RandomColor, multiplier: u8) {
-        ProcMap::print_block_color(names, len, col.next(), multiplier);
+        ret.extend(spare);
+        ret
     }
 
-    pub(crate) fn display(&self) {
-        let mut col = RandomColor::init();
+    /// Try the job policy and then its fallback one, if any
+    fn map_with_fallback(
+        &mut self,
+        job: &JobEntry,
+        size: usize,
+        on_each: bool,
+        filter: &NumaFilter,
+    ) -> Result<()> {
+        let cp = self.checkpoint();
 
-        let multiplier = match self.count() {
-            0..=10 => 10,
-            11..=20 => 8,
-            21..=40 => 4,
-            _ => 1,
+        let err = match self.map_for_defined_size(job, job.policy(), size, on_each, filter) {
+            Ok(()) => {
+                self.commit(cp);
+                return Ok(());
+            }
+            Err(e) => e,
         };
 
-        ProcMap::print_block(
-            vec!["Whole System".to_string(), "System".to_string()],
-            self.count() as usize,
-            &mut col,
-            multiplier,
-        );
+        self.rollback(cp);
 
-        println!();
+        let fallback = match job.fallback {
+            Some(p) => p,
+            None => return Err(err),
+        };
 
-        for (cnt, (host, node)) in self.nodes.iter().enumerate() {
-            ProcMap::print_block(
-                vec![
-                    format!("Node {} : {}", cnt, host),
-                    host.clone(),
-                    format!("{}", cnt),
-                ],
-                node.count() as usize,
-                &mut col,
-                multiplier,
+        if self.explain {
+            println!(
+                "[explain] {}: {:?} policy failed ({}), falling back to {:?}",
+                job.label(),
+                job.policy(),
+                err,
+                fallback
             );
         }
 
-        println!();
+        let cp = self.checkpoint();
 
-        /* NUMA */
-        for (_, node) in self.nodes.iter() {
-            for (_, numa) in node.numas.iter() {
-                ProcMap::print_block(
-                    vec![format!("NUMA {}", numa.id), format!("{}", numa.id)],
-                    numa.count() as usize,
-                    &mut col,
-                    multiplier,
-                );
+        match self.map_for_defined_size(job, fallback, size, on_each, filter) {
+            Ok(()) => {
+                self.commit(cp);
+                Ok(())
+            }
+            Err(e) => {
+                self.rollback(cp);
+                Err(anyhow!("{} (fallback {:?} policy: {})", err, fallback, e))
             }
         }
+    }
 
-        println!();
+    fn map_for_defined_size(
+        &mut self,
+        job: &JobEntry,
+        policy: Policy,
+        size: usize,
+        on_each: bool,
+        filter: &NumaFilter,
+    ) -> Result<()> {
+        let level = job.loc_or_slot();
+        let level = level.as_str();
+        let nodes = self.free_slots(filter, job);
+
+        /* Free slots grouped by domain at the requested level */
+        let domains: Vec<Vec<SlotRef>> = match level {
+            "node" => nodes
+                .iter()
+                .filter(|n| !n.is_empty())
+                .map(|n| n.concat())
+                .collect(),
+            "numa" | "slot" => nodes.iter().flatten().cloned().collect(),
+            _ => {
+                return Err(anyhow!("No such locality specifier {}", level));
+            }
+        };
 
-        /* SLOT */
-        for (_, node) in self.nodes.iter() {
-            for (_, numa) in node.numas.iter() {
-                /* Print the slots */
-                let by_rank = numa.count_by_rank();
-                for ((rank, job), count) in by_rank {
-                    if let Some(job) = job {
-                        ProcMap::print_block_color(
-                            vec![
-                                format!("Rank {} Job {}", rank, job),
-                                format!("R:{} J: {}", rank, job),
-                                format!("R{}J{}", rank, job),
+        let candidates = match policy {
+            Policy::Compact => domains.concat(),
+            Policy::Cyclic => {
+                if on_each && !domains.is_empty() {
+                    /* Each domain has to provide its share of the round-robin */
+                    let quantum = size / domains.len();
+                    let rest = size - (quantum * domains.len());
+
+                    for (i, d) in domains.iter().enumerate() {
+                        let want = if i < rest { quantum + 1 } else { quantum };
+                        if d.len() < want {
+                            return Err(anyhow!(
+                                "No room on {} to allocate {} slots cyclically ({} left)",
+                                level,
+                                size,
+                                d.len()
+                            ));
+                        }
+                    }
+                }
+                ProcMap::interleave(domains)
+            }
+            Policy::Scatter => {
+                ProcMap::interleave(nodes.into_iter().map(ProcMap::interleave).collect())
+            }
+            Policy::Block => ProcMap::blocks(domains, size),
+        };
+
+        if candidates.len() < size {
+            /* Not enough slots */
+            return Err(anyhow!(
+                "Not enough slots available to allocate {} slots on {} ({} left)",
+                size,
+                level,
+                candidates.len()
+            ));
+        }
+
+        let cp = self.checkpoint();
+
+        for s in candidates.iter().take(size) {
+            if let Err(e) = self.acquire_rank(s, job) {
+                self.rollback(cp);
+                return Err(e);
+            }
+        }
+
+        self.commit(cp);
+
+        if self.explain {
+            self.explain_taken(job, &candidates[..size]);
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn set_explain(&mut self, explain: bool) {
+        self.explain = explain;
+    }
+
+    /// Map all jobs, on failure the map is left as it was before the call
+    pub(crate) fn map(&mut self, jobs: &mut JobList) -> Result<()> {
+        let cp = self.checkpoint();
+
+        let greedy_err = match self.map_greedy(jobs) {
+            Ok(()) => {
+                self.commit(cp);
+                if self.explain {
+                    println!("{}", self.occupancy(jobs));
+                }
+                return Ok(());
+            }
+            Err(e) => e,
+        };
+
+        if self.explain {
+            println!("[explain] greedy mapping failed: {}", greedy_err);
+            println!("{}", self.occupancy(jobs));
+            println!("[explain] searching for another placement");
+        }
+
+        /* Greedy order failed, search for another placement */
+        self.rollback(cp);
+        let cp = self.checkpoint();
+
+        match solver::solve(self, jobs) {
+            Ok(()) => {
+                self.commit(cp);
+                if self.explain {
+                    for j in jobs.iter() {
+                        self.explain_taken(j, &self.job_slots(j.id));
+                    }
+                    println!("{}", self.occupancy(jobs));
+                }
+                Ok(())
+            }
+            Err(report) => {
+                self.rollback(cp);
+                Err(anyhow!("Greedy mapping failed: {}\n{}", greedy_err, report))
+            }
+        }
+    }
+
+    /// NUMA domains accepted by a filter in map order
+    fn allowed_numas(&self, filter: &NumaFilter) -> Vec<NumaRef> {
+        self.nodes
+            .iter()
+            .flat_map(|(host, node)| {
+                node.numas
+                    .keys()
+                    .filter(|id| filter.accepts(host, **id))
+                    .map(|id| (host.clone(), *id))
+            })
+            .collect()
+    }
+
+    /// Slots holding ranks of the given job
+    fn job_slots(&self, jobid: u32) -> Vec<SlotRef> {
+        let mut ret = Vec::new();
+
+        for (host, node) in self.nodes.iter() {
+            for (id, numa) in node.numas.iter() {
+                for (idx, s) in numa.slots.iter().enumerate() {
+                    if s.job == Some(jobid) {
+                        ret.push((host.clone(), *id, idx));
+                    }
+                }
+            }
+        }
+
+        ret
+    }
+
+    /// Log the slots a job just took per NUMA with the capacity left there
+    fn explain_taken(&self, job: &JobEntry, taken: &[SlotRef]) {
+        let mut per_numa: Vec<(NumaRef, usize)> = Vec::new();
+
+        for (host, numa, _) in taken {
+            match per_numa
+                .iter_mut()
+                .find(|(n, _)| n.0 == *host && n.1 == *numa)
+            {
+                Some((_, cnt)) => *cnt += 1,
+                None => per_numa.push(((host.clone(), *numa), 1)),
+            }
+        }
+
+        for ((host, numa), cnt) in per_numa {
+            let free = self
+                .nodes
+                .get(&host)
+                .and_then(|n| n.numas.get(&numa))
+                .map(|n| n.slots.iter().filter(|s| s.is_free()).count())
+                .unwrap_or(0);
+
+            println!(
+                "[explain] {} ({}, level {}): took {} slot(s) on {} NUMA {}, {} free left",
+                job.label(),
+                job.map,
+                job.loc_or_slot(),
+                cnt,
+                host,
+                numa,
+                free
+            );
+        }
+    }
+
+    /// Per NUMA table of the slots used by each job, reserved and free
+    pub(crate) fn occupancy(&self, jobs: &JobList) -> String {
+        let mut rows: Vec<[String; 6]> = vec![[
+            "Host".to_string(),
+            "NUMA".to_string(),
+            "Slots".to_string(),
+            "Free".to_string(),
+            "Reserved".to_string(),
+            "Jobs".to_string(),
+        ]];
+
+        for (host, node) in self.nodes.iter() {
+            for (id, numa) in node.numas.iter() {
+                let mut per_job: BTreeMap<u32, usize> = BTreeMap::new();
+                for s in numa.slots.iter() {
+                    if let Some(j) = s.job {
+                        *per_job.entry(j).or_default() += 1;
+                    }
+                }
+
+                let used = per_job
+                    .iter()
+                    .map(|(j, cnt)| match jobs.job_by_id(*j) {
+                        Some(j) => format!("{}:{}", j.label(), cnt),
+                        None => format!("{}:{}", j, cnt),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ");
+
+                rows.push([
+                    host.clone(),
+                    id.to_string(),
+                    numa.slots.len().to_string(),
+                    numa.slots
+                        .iter()
+                        .filter(|s| s.is_free())
+                        .count()
+                        .to_string(),
+                    numa.slots
+                        .iter()
+                        .filter(|s| s.reserved.is_some())
+                        .count()
+                        .to_string(),
+                    used,
+                ]);
+            }
+        }
+
+        let mut widths = [0; 6];
+        for row in rows.iter() {
+            for (w, cell) in widths.iter_mut().zip(row.iter()) {
+                *w = (*w).max(cell.len());
+            }
+        }
+
+        rows.iter()
+            .map(|row| {
+                row.iter()
+                    .zip(widths)
+                    .map(|(cell, w)| format!("{:w$}", cell, w = w))
+                    .collect::<Vec<_>>()
+                    .join("  ")
+                    .trim_end()
+                    .to_string()
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn map_greedy(&mut self, jobs: &JobList) -> Result<()> {
+        self.map_each_jobs(jobs)?;
+
+        /* Now we map fixed JOBs */
+        for j in jobs.fixed_jobs() {
+            self.map_fixed_job(jobs, j)?;
+        }
+
+        self.map_all_jobs(jobs)
+    }
+
+    fn map_each_jobs(&mut self, jobs: &JobList) -> Result<()> {
+        /* We start by mapping "for each" jobs */
+        for j in jobs.each_jobs() {
+            let jobid = j.id;
+            let filter = self.numa_filter(jobs, j)?;
+
+            /* Pick the slots first and acquire them afterwards */
+            let mut taken: Vec<SlotRef> = Vec::new();
+
+            /* Free slots of each domain at the job level */
+            let domains: Vec<(String, Vec<SlotRef>)> = match j.loc.as_deref() {
+                Some("numa") => self
+                    .free_slots(&filter, j)
+                    .into_iter()
+                    .flatten()
+                    .zip(self.allowed_numas(&filter))
+                    .map(|(free, (host, id))| (format!("{} NUMA {}", host, id), free))
+                    .collect(),
+                Some("node") => self
+                    .free_slots(&filter, j)
+                    .into_iter()
+                    .zip(self.nodes.values())
+                    .filter(|(_, n)| n.numas.keys().any(|id| filter.accepts(&n.host, *id)))
+                    .map(|(free, n)| {
+                        /* Walk the NUMA domains of the node following the policy */
+                        let ordered = match j.policy() {
+                            Policy::Compact => free.concat(),
+                            Policy::Cyclic | Policy::Scatter => ProcMap::interleave(free),
+                            Policy::Block => ProcMap::blocks(free, j.count),
+                        };
+                        (n.host.clone(), ordered)
+                    })
+                    .collect(),
+                Some("slot") => {
+                    if j.strict {
+                        for (host, id) in self.allowed_numas(&filter) {
+                            let numa = &self.nodes[&host].numas[&id];
+                            if numa.slots.iter().any(|s| !s.is_free()) {
+                                return Err(anyhow!(
+                                    "Job {} ({}) requires every slot but {} NUMA {} has some taken",
+                                    j.label(),
+                                    j.map,
+                                    host,
+                                    id
+                                ));
+                            }
+                        }
+                    }
+                    vec![(
+                        "slots".to_string(),
+                        self.free_slots(&filter, j).concat().concat(),
+                    )]
+                }
+                Some(loc) => {
+                    return Err(anyhow!("No such locality specifier {}", loc));
+                }
+                None => unreachable!("All Each specifier has to provide a locality"),
+            };
+
+            let per_domain = if j.loc.as_deref() == Some("slot") {
+                usize::MAX
+            } else {
+                j.count
+            };
+
+            for (name, free) in domains {
+                if j.loc.as_deref() != Some("slot") && free.len() < j.count {
+                    if j.strict {
+                        return Err(anyhow!(
+                            "Job {} ({}) requires {} free slot(s) on every {} but {} has {}",
+                            j.label(),
+                            j.map,
+                            j.count,
+                            j.loc_or_slot(),
+                            name,
+                            free.len()
+                        ));
+                    }
+
+                    if self.explain {
+                        println!(
+                            "[explain] {} ({}): skipping {} which has {} free slot(s)",
+                            j.label(),
+                            j.map,
+                            name,
+                            free.len()
+                        );
+                    }
+                    continue;
+                }
+
+                taken.extend(free.into_iter().take(per_domain));
+            }
+
+            if taken.is_empty() {
+                return Err(anyhow!(
+                    "Job {} ({}) found no {} with {} free slot(s)",
+                    j.label(),
+                    j.map,
+                    j.loc_or_slot(),
+                    j.count
+                ));
+            }
+
+            let cp = self.checkpoint();
+            for slot in taken.iter() {
+                if let Err(e) = self.acquire_rank(slot, j) {
+                    self.rollback(cp);
+                    return Err(e);
+                }
+            }
+            self.commit(cp);
+
+            if self.explain {
+                self.explain_taken(j, &self.job_slots(jobid));
+            }
+
+            self.reserve_exclusive(j);
+        }
+
+        /* Each jobs got their size from the topology, make sure it fits */
+        for j in jobs.each_jobs() {
+            let jobid = j.id;
+            let count = self.count_job_slots(jobid) / j.cores_per_rank;
+            if !j.accepts_rank_count(count) {
+                return Err(anyhow!(
+                    "Job {} ({}) got {} ranks which does not satisfy its rank constraints",
+                    j.label(),
+                    j.map,
+                    count
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn map_fixed_job(&mut self, jobs: &JobList, j: &JobEntry) -> Result<()> {
+        let number_to_alloc = j.fixed_size()?;
+
+        /* Now we want to acquire as many as per fixed using the correct walk logic */
+        self.map_with_fallback(j, number_to_alloc, true, &self.numa_filter(jobs, j)?)?;
+
+        self.reserve_exclusive(j);
+
+        Ok(())
+    }
+
+    fn map_all_jobs(&mut self, jobs: &JobList) -> Result<()> {
+        /* Eventually we map the "all" jobs */
+        let remaining_slots = self.count_free_slots();
+        let sizes = ProcMap::share_all_jobs(jobs, remaining_slots)?;
+
+        if self.explain {
+            for (j, size) in jobs.all_jobs().zip(sizes.iter()) {
+                println!(
+                    "[explain] {} ({}): share of {} slot(s) out of {} free",
+                    j.label(),
+                    j.map,
+                    size,
+                    remaining_slots
+                );
+            }
+        }
+
+        for (j, share) in jobs.all_jobs().zip(sizes) {
+            let filter = self.numa_filter(jobs, j)?;
+
+            /* Affinity constraints may leave less room than the even share */
+            let available: usize = self
+                .free_slots(&filter, j)
+                .iter()
+                .flatten()
+                .map(|v| v.len())
+                .sum();
+            /* Shares are counted in slots, wider ranks take several of them */
+            let ranks = share / j.cores_per_rank;
+            let tsize = if available < ranks || j.cores_per_rank != 1 {
+                j.fit_rank_count(available.min(ranks))
+            } else {
+                ranks
+            };
+
+            if tsize == 0 {
+                return Err(anyhow!(
+                    "Job {} ({}) has no room left in the NUMA domains its constraints allow",
+                    j.label(),
+                    j.map
+                ));
+            }
+
+            if j.exclusive.is_none() {
+                self.map_with_fallback(j, tsize, false, &filter)?;
+                continue;
+            }
+
+            /* Exclusive jobs account for the slots they reserve: shrink the job
+            until ranks and reserved slots fit in its share */
+            let mut candidate = tsize;
+            let mut held = 0;
+
+            while 0 < candidate {
+                let cp = self.checkpoint();
+
+                if let Err(e) = self.map_with_fallback(j, candidate, false, &filter) {
+                    self.rollback(cp);
+                    return Err(e);
+                }
+                self.reserve_exclusive(j);
+
+                held = self.count_held_slots(j.id);
+                if held <= share {
+                    self.commit(cp);
+                    break;
+                }
+
+                self.rollback(cp);
+                candidate = j.fit_rank_count(candidate - 1);
+
+                if self.explain {
+                    println!(
+                        "[explain] {}: ranks and reserved slots exceed its share of {}, retrying with {} rank(s)",
+                        j.label(),
+                        share,
+                        candidate
+                    );
+                }
+            }
+
+            /* Overflowing the share would starve the next "all" jobs */
+            if candidate == 0 {
+                return Err(anyhow!(
+                    "Exclusive job {} ({}) does not fit in its share of {} slot(s): its smallest placement holds {} slot(s) with the ones it reserves",
+                    j.label(),
+                    j.map,
+                    share,
+                    held
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn count_held_slots(&self, jobid: u32) -> usize {
+        self.slots().filter(|s| s.held_by(jobid)).count()
+    }
+
+    /// Reserve the free slots of the domains where an exclusive job got slots
+    fn reserve_exclusive(&mut self, job: &JobEntry) {
+        let exclusive = match job.exclusive {
+            Some(e) => e,
+            None => return,
+        };
+
+        let mut to_reserve: Vec<SlotRef> = Vec::new();
+
+        for node in self.nodes.values() {
+            let node_used = node
+                .numas
+                .values()
+                .any(|nu| nu.slots.iter().any(|s| s.job == Some(job.id)));
+
+            for nu in node.numas.values() {
+                let used = match exclusive {
+                    Exclusive::Node => node_used,
+                    Exclusive::Numa => nu.slots.iter().any(|s| s.job == Some(job.id)),
+                };
+
+                if !used {
+                    continue;
+                }
+
+                let before = to_reserve.len();
+                for (idx, s) in nu.slots.iter().enumerate() {
+                    if s.is_free() {
+                        to_reserve.push((node.host.clone(), nu.id, idx));
+                    }
+                }
+
+                if self.explain && before != to_reserve.len() {
+                    println!(
+                        "[explain] {} (exclusive {:?}): reserved {} slot(s) on {} NUMA {}",
+                        job.label(),
+                        exclusive,
+                        to_reserve.len() - before,
+                        node.host,
+                        nu.id
+                    );
+                }
+            }
+        }
+
+        for slot in to_reserve.iter() {
+            self.reserve_slot(slot, job.id);
+        }
+    }
+
+    /// NUMA domains holding at least one slot of the given job
+    fn job_numas(&self, jobid: u32) -> HashSet<NumaRef> {
+        let mut ret = HashSet::new();
+
+        for (host, node) in self.nodes.iter() {
+            for (id, numa) in node.numas.iter() {
+                if numa.slots.iter().any(|s| s.job == Some(jobid)) {
+                    ret.insert((host.clone(), *id));
+                }
+            }
+        }
+
+        ret
+    }
+
+    /// Compute the NUMA domains a job may use from its colocate_with and avoid
+    /// constraints, avoid is symmetric so jobs avoiding this one are also excluded
+    fn numa_filter(&self, jobs: &JobList, job: &JobEntry) -> Result<NumaFilter> {
+        let mut filter = NumaFilter::any();
+        /* First reason each domain got denied for, to explain conflicts */
+        let mut why: HashMap<NumaRef, &str> = HashMap::new();
+        let mut deny = |filter: &mut NumaFilter, numas: Vec<NumaRef>, reason| {
+            for n in numas {
+                why.entry(n.clone()).or_insert(reason);
+                filter.deny.insert(n);
+            }
+        };
+
+        /* Nodes outside of the job selection are denied */
+        if let Some(sel) = job.nodes.as_ref() {
+            let hosts: Vec<String> = self.nodes.keys().cloned().collect();
+            let selected = sel
+                .select(&hosts)
+                .map_err(|e| anyhow!("Bad node selection for job {} : {}", job.label(), e))?;
+
+            for (host, node) in self.nodes.iter() {
+                if !selected.contains(host) {
+                    deny(
+                        &mut filter,
+                        node.numas.keys().map(|id| (host.clone(), *id)).collect(),
+                        "its node selection",
+                    );
+                }
+            }
+        }
+
+        /* Nodes lacking one of the requested features are denied */
+        if !job.features.is_empty() {
+            let mut found = false;
+
+            for (host, node) in self.nodes.iter() {
+                if job.features.iter().all(|f| node.features.tags.contains(f)) {
+                    found = true;
+                } else {
+                    deny(
+                        &mut filter,
+                        node.numas.keys().map(|id| (host.clone(), *id)).collect(),
+                        "its features",
+                    );
+                }
+            }
+
+            if !found {
+                return Err(anyhow!(
+                    "No node provides features {} for job {}",
+                    job.features.join(","),
+                    job.label()
+                ));
+            }
+        }
+
+        if let Some(target) = job.colocate_with.as_ref() {
+            let numas = self.job_numas(jobs.job_by_name(target)?.id);
+
+            if numas.is_empty() {
+                return Err(anyhow!(
+                    "Job {} colocates with {} which is not mapped yet (\"each\" jobs are mapped first, then fixed ones and eventually \"all\" ones, in jobfile order)",
+                    job.label(),
+                    target
+                ));
+            }
+
+            filter.allow = Some(numas);
+        }
+
+        if let Some(target) = job.avoid.as_ref() {
+            let numas = self.job_numas(jobs.job_by_name(target)?.id);
+            deny(&mut filter, numas.into_iter().collect(), "anti-affinity");
+        }
+
+        for other in jobs.avoiding(job) {
+            let numas = self.job_numas(other.id);
+            deny(&mut filter, numas.into_iter().collect(), "anti-affinity");
+        }
+
+        /* Exclusive jobs cannot use domains already holding other jobs */
+        if let Some(exclusive) = job.exclusive {
+            for (host, node) in self.nodes.iter() {
+                let node_shared = node
+                    .numas
+                    .values()
+                    .any(|nu| nu.slots.iter().any(|s| !s.is_free() && !s.held_by(job.id)));
+
+                for (id, nu) in node.numas.iter() {
+                    let shared = match exclusive {
+                        Exclusive::Node => node_shared,
+                        Exclusive::Numa => {
+                            nu.slots.iter().any(|s| !s.is_free() && !s.held_by(job.id))
+                        }
+                    };
+
+                    if shared {
+                        deny(&mut filter, vec![(host.clone(), *id)], "its exclusivity");
+                    }
+                }
+            }
+        }
+
+        if let Some(allow) = filter.allow.as_ref() {
+            if allow.iter().all(|n| filter.deny.contains(n)) {
+                let mut reasons: Vec<&str> =
+                    allow.iter().filter_map(|n| why.get(n).copied()).collect();
+                reasons.sort();
+                reasons.dedup();
+
+                return Err(anyhow!(
+                    "Conflicting constraints for job {}: every NUMA domain of {} is excluded by {}",
+                    job.label(),
+                    job.colocate_with.as_deref().unwrap_or_default(),
+                    reasons.join(" and ")
+                ));
+            }
+        }
+
+        Ok(filter)
+    }
+
+    fn count_job_slots(&mut self, jobid: u32) -> usize {
+        self.each_slot().filter(|v| v.job == Some(jobid)).count()
+    }
+
+    /// Split the remaining slots between "all" jobs honoring rank constraints
+    /// slots which cannot be used by constrained jobs are given to unconstrained
+    /// ones, if there are none they are left idle
+    fn share_all_jobs(jobs: &JobList, remaining_slots: usize) -> Result<Vec<usize>> {
+        let all_job_count = jobs.all_jobs_count();
+
+        let quantum = match remaining_slots.checked_div(all_job_count) {
+            Some(q) => q,
+            None => return Ok(Vec::new()),
+        };
+        let rest = remaining_slots - (all_job_count * quantum);
+
+        /* We give the rest to the first job */
+        let mut sizes: Vec<usize> = (0..all_job_count)
+            .map(|i| if i == 0 { quantum + rest } else { quantum })
+            .collect();
+
+        /* Shrink constrained jobs to their closest valid size */
+        let mut released = 0;
+
+        for (j, size) in jobs.all_jobs().zip(sizes.iter_mut()) {
+            let fit = j.fit_rank_count(*size);
+            released += *size - fit;
+            *size = fit;
+        }
+
+        /* Give released slots to unconstrained jobs */
+        let free_jobs = jobs.all_jobs().filter(|j| !j.has_rank_constraint()).count();
+
+        if let Some(quantum) = released.checked_div(free_jobs) {
+            let mut rest = released - (free_jobs * quantum);
+
+            for (j, size) in jobs.all_jobs().zip(sizes.iter_mut()) {
+                if j.has_rank_constraint() {
+                    continue;
+                }
+                *size += quantum + rest;
+                rest = 0;
+            }
+        } else {
+            /* Only constrained jobs, let them grow if they can */
+            for (j, size) in jobs.all_jobs().zip(sizes.iter_mut()) {
+                let fit = j.fit_rank_count(*size + released);
+                released -= fit - *size;
+                *size = fit;
+            }
+        }
+
+        for (j, size) in jobs.all_jobs().zip(sizes.iter()) {
+            if *size == 0 {
+                return Err(anyhow!(
+                    "Cannot satisfy rank constraints of job {} with {} free slots",
+                    j.label(),
+                    remaining_slots
+                ));
+            }
+        }
+
+        Ok(sizes)
+    }
+
+    fn print_block_color(names: Vec<String>, len: usize, col: (u8, u8, u8), multiplier: u8) {
+        if len == 0 {
+            return;
+        }
+
+        let len = len * multiplier as usize;
+
+        let mut text: Option<String> = None;
+
+        for s in names {
+            if s.len() < len {
+                text = Some(s);
+                break;
+            }
+        }
+
+        let mut left = len;
+
+        if let Some(s) = text {
+            print!("{}", s.black().on_rgb(col.0, col.1, col.2));
+            left -= s.len();
+        }
+
+        for _ in 0..left {
+            print!("{}", " ".on_rgb(col.0, col.1, col.2));
+        }
+    }
+
+    fn print_block(names: Vec<String>, len: usize, col: &mut RandomColor, multiplier: u8) {
+        ProcMap::print_block_color(names, len, col.next(), multiplier);
+    }
+
+    pub(crate) fn display(&self, jobs: Option<&JobList>) {
+        let mut col = RandomColor::init();
+
+        let multiplier = match self.count() {
+            0..=10 => 10,
+            11..=20 => 8,
+            21..=40 => 4,
+            _ => 1,
+        };
+
+        ProcMap::print_block(
+            vec!["Whole System".to_string(), "System".to_string()],
+            self.count() as usize,
+            &mut col,
+            multiplier,
+        );
+
+        println!();
+
+        for (cnt, (host, node)) in self.nodes.iter().enumerate() {
+            ProcMap::print_block(
+                vec![
+                    format!("Node {} : {}", cnt, host),
+                    host.clone(),
+                    format!("{}", cnt),
+                ],
+                node.count() as usize,
+                &mut col,
+                multiplier,
+            );
+        }
+
+        println!();
+
+        /* NUMA */
+        for (_, node) in self.nodes.iter() {
+            for (_, numa) in node.numas.iter() {
+                ProcMap::print_block(
+                    vec![format!("NUMA {}", numa.id), format!("{}", numa.id)],
+                    numa.count() as usize,
+                    &mut col,
+                    multiplier,
+                );
+            }
+        }
+
+        println!();
+
+        /* SLOT */
+        for (_, node) in self.nodes.iter() {
+            for (_, numa) in node.numas.iter() {
+                /* Print the slots */
+                let by_rank = numa.count_by_rank();
+                for ((rank, job), count) in by_rank {
+                    if let Some(job) = job {
+                        let label = match jobs.and_then(|j| j.job_by_id(job)) {
+                            Some(j) => j.label(),
+                            None => format!("{}", job),
+                        };
+                        ProcMap::print_block_color(
+                            vec![
+                                format!("Rank {} Job {}", rank, label),
+                                format!("R:{} J: {}", rank, label),
+                                format!("R{}J{}", rank, label),
+                                label.clone(),
                                 format!("{}", job),
                             ],
                             count as usize,
-                            col.id(job as u32),
+                            col.id(job),
                             multiplier,
                         );
                     } else {
@@ -624,6 +1762,38 @@ impl ProcMap {
         }
 
         println!();
+
+        /* Only worth showing when discovery reported something */
+        if self
+            .nodes
+            .values()
+            .any(|n| n.features != NodeFeatures::default())
+        {
+            for (cnt, (host, node)) in self.nodes.iter().enumerate() {
+                println!("Node {} : {} : {}", cnt, host, node.features.describe());
+            }
+            println!();
+        }
+    }
+
+    fn parse_discovery(output: &str) -> Vec<JobDesc> {
+        let mut ret: Vec<JobDesc> = Vec::new();
+
+        // Capture input line by line
+        for line in output.lines() {
+            if let Ok(jd) = serde_json::from_str(line) {
+                ret.push(jd);
+            } else {
+                println!("Failed to parse JobDesc : {}", line);
+            }
+        }
+
+        ret
+    }
+
+    fn load_discovery(file: PathBuf) -> Result<Vec<JobDesc>> {
+        let output = std::fs::read_to_string(file)?;
+        Ok(ProcMap::parse_discovery(&output))
     }
 
     fn discovery() -> Result<Vec<JobDesc>> {
@@ -645,17 +1815,469 @@ impl ProcMap {
 
         srun.stdout.take().unwrap().read_to_string(&mut output)?;
 
-        let mut ret: Vec<JobDesc> = Vec::new();
+        Ok(ProcMap::parse_discovery(&output))
+    }
+}
 
-        // Capture input line by line
-        for line in output.lines() {
-            if let Ok(jd) = serde_json::from_str(line) {
-                ret.push(jd);
-            } else {
-                println!("Failed to parse JobDesc : {}", line);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Nodes n0, n1... with NUMA domains of single-PU slots, one task per slot
+    fn topology(nodes: usize, numas: usize, slots: usize) -> ProcMap {
+        let mut descs = Vec::new();
+        for n in 0..nodes {
+            for numa in 0..numas {
+                for s in 0..slots {
+                    let pu = numa * slots + s;
+                    descs.push(JobDesc {
+                        host: format!("n{}", n),
+                        rank: descs.len() as u32,
+                        numa: vec![numa],
+                        pu: vec![vec![pu]],
+                        core: vec![vec![pu]],
+                        features: NodeFeatures::default(),
+                    });
+                }
+            }
+        }
+        ProcMap::from_descs(&descs)
+    }
+
+    /// Map the jobs of a jobfile given as YAML
+    fn map(pmap: &mut ProcMap, yaml: &str) -> Result<JobList> {
+        let mut jobs = JobList::parse(yaml)?;
+        pmap.map(&mut jobs)?;
+        Ok(jobs)
+    }
+
+    /// Domains (nX.Y) of the slots a job holds
+    fn placed(pmap: &ProcMap, jobid: u32) -> Vec<String> {
+        pmap.job_slots(jobid)
+            .into_iter()
+            .map(|(host, numa, _)| format!("{}.{}", host, numa))
+            .collect()
+    }
+
+    fn policy(policy: &str, size: usize) -> Vec<String> {
+        let mut pmap = topology(2, 2, 2);
+        let yaml = format!(
+            "- map: {}numa\n  command: [a]\n  policy: {}\n",
+            size, policy
+        );
+        map(&mut pmap, &yaml).unwrap();
+        placed(&pmap, 0)
+    }
+
+    #[test]
+    fn policies_two_slots() {
+        assert_eq!(policy("compact", 2), ["n0.0", "n0.0"]);
+        assert_eq!(policy("scatter", 2), ["n0.0", "n1.0"]);
+        assert_eq!(policy("cyclic", 2), ["n0.0", "n0.1"]);
+        assert_eq!(policy("block", 2), ["n0.0", "n0.1"]);
+    }
+
+    #[test]
+    fn policies_five_over_four_numas() {
+        assert_eq!(
+            policy("compact", 5),
+            ["n0.0", "n0.0", "n0.1", "n0.1", "n1.0"]
+        );
+        assert_eq!(
+            policy("scatter", 5),
+            ["n0.0", "n0.0", "n0.1", "n1.0", "n1.1"]
+        );
+        /* 2,1,1,1 */
+        assert_eq!(
+            policy("cyclic", 5),
+            ["n0.0", "n0.0", "n0.1", "n1.0", "n1.1"]
+        );
+        /* 2,2,1,0 */
+        assert_eq!(policy("block", 5), ["n0.0", "n0.0", "n0.1", "n0.1", "n1.0"]);
+    }
+
+    #[test]
+    fn release_is_journaled() {
+        let mut pmap = topology(1, 2, 2);
+        map(&mut pmap, "- map: 3slot\n  command: [a]\n").unwrap();
+        assert!(pmap.journal.is_empty());
+
+        let cp = pmap.checkpoint();
+        assert_eq!(pmap.release(0), 3);
+        assert_eq!(pmap.count_free_slots(), 4);
+        pmap.rollback(cp);
+        assert_eq!(placed(&pmap, 0).len(), 3);
+
+        /* Outside of a checkpoint nothing is kept */
+        assert_eq!(pmap.release(0), 3);
+        assert!(pmap.journal.is_empty());
+    }
+
+    /* n0.0 fully reserved, n0.1 with a single free slot, n0.2 free */
+    fn crowded() -> ProcMap {
+        let mut pmap = topology(1, 3, 3);
+        for idx in 0..3 {
+            pmap.reserve_slot(&("n0".to_string(), 0, idx), 99);
+        }
+        for idx in 0..2 {
+            pmap.acquire_slot(&("n0".to_string(), 1, idx), 98).unwrap();
+        }
+        pmap
+    }
+
+    #[test]
+    fn each_skips_full_and_small_domains() {
+        let mut pmap = crowded();
+        map(&mut pmap, "- map: E2numa\n  command: [a]\n").unwrap();
+        assert_eq!(placed(&pmap, 0), ["n0.2", "n0.2"]);
+
+        let mut pmap = crowded();
+        map(&mut pmap, "- map: Enuma\n  command: [a]\n").unwrap();
+        assert_eq!(placed(&pmap, 0), ["n0.1", "n0.2"]);
+    }
+
+    #[test]
+    fn strict_each_fails_on_full_domain() {
+        let mut pmap = crowded();
+        let err = map(&mut pmap, "- map: Enuma!\n  command: [a]\n").unwrap_err();
+        assert!(err.to_string().contains("n0 NUMA 0 has 0"), "{}", err);
+        /* Nothing is left taken by the failed job */
+        assert!(placed(&pmap, 0).is_empty());
+    }
+
+    #[test]
+    fn each_counts() {
+        let mut pmap = topology(2, 2, 3);
+        map(&mut pmap, "- map: E2numa\n  command: [a]\n").unwrap();
+        assert_eq!(
+            placed(&pmap, 0),
+            ["n0.0", "n0.0", "n0.1", "n0.1", "n1.0", "n1.0", "n1.1", "n1.1"]
+        );
+
+        let mut pmap = topology(2, 2, 3);
+        map(&mut pmap, "- map: E4node\n  command: [a]\n").unwrap();
+        let held = placed(&pmap, 0);
+        assert_eq!(held.len(), 8);
+        assert_eq!(held.iter().filter(|d| d.starts_with("n0.")).count(), 4);
+    }
+
+    #[test]
+    fn strict_each_slot_needs_every_slot() {
+        let mut pmap = topology(1, 2, 2);
+        pmap.acquire_slot(&("n0".to_string(), 1, 0), 98).unwrap();
+
+        let err = map(&mut pmap, "- map: Eslot!\n  command: [a]\n").unwrap_err();
+        assert!(err.to_string().contains("requires every slot"), "{}", err);
+
+        map(&mut pmap, "- map: Eslot\n  command: [a]\n").unwrap();
+        assert_eq!(placed(&pmap, 0), ["n0.0", "n0.0", "n0.1"]);
+    }
+
+    #[test]
+    fn wide_ranks_use_adjacent_pus() {
+        /* Discovery lines in no particular order */
+        let descs: Vec<JobDesc> = [3, 1, 0, 2]
+            .iter()
+            .enumerate()
+            .map(|(rank, pu)| JobDesc {
+                host: "n0".to_string(),
+                rank: rank as u32,
+                numa: vec![0],
+                pu: vec![vec![*pu]],
+                core: vec![vec![*pu]],
+                features: NodeFeatures::default(),
+            })
+            .collect();
+        let mut pmap = ProcMap::from_descs(&descs);
+
+        let jobs = map(
+            &mut pmap,
+            "- map: 2slot\n  command: [a]\n  cores_per_rank: 2\n",
+        )
+        .unwrap();
+        let pus: Vec<Vec<usize>> = pmap
+            .to_plan(&jobs)
+            .ranks
+            .into_iter()
+            .map(|r| r.pus)
+            .collect();
+        assert_eq!(pus, [[0, 1], [2, 3]]);
+    }
+
+    #[test]
+    fn sibling_hyperthreads_of_two_tasks() {
+        /* One task per hardware thread, PUs 0 and 1 are the threads of core 0 */
+        let descs: Vec<JobDesc> = (0..2)
+            .map(|pu| JobDesc {
+                host: "n0".to_string(),
+                rank: pu as u32,
+                numa: vec![0],
+                pu: vec![vec![pu]],
+                core: vec![vec![0]],
+                features: NodeFeatures::default(),
+            })
+            .collect();
+        let mut pmap = ProcMap::from_descs(&descs);
+
+        let smt: Vec<Vec<usize>> = pmap.slots().map(|s| s.smt.clone()).collect();
+        assert_eq!(smt, [[0], [1]]);
+
+        let jobs = map(
+            &mut pmap,
+            "- map: A\n  command: [sim]\n  smt: off\n- map: A\n  command: [monitor]\n  smt: pair\n",
+        )
+        .unwrap();
+        let pus: Vec<(String, Vec<usize>)> = pmap
+            .to_plan(&jobs)
+            .ranks
+            .into_iter()
+            .map(|r| (r.job, r.pus))
+            .collect();
+        assert_eq!(
+            pus,
+            [("job0".to_string(), vec![0]), ("job1".to_string(), vec![1])]
+        );
+    }
+
+    fn shares(yaml: &str, slots: usize) -> Result<Vec<usize>> {
+        ProcMap::share_all_jobs(&JobList::parse(yaml)?, slots)
+    }
+
+    #[test]
+    fn all_job_shares() {
+        /* (jobs as map and constraints, free slots, expected shares) */
+        let table: [(&[&str], usize, &[usize]); 6] = [
+            /* The rest of the division goes to the first job */
+            (&["", "", ""], 10, &[4, 3, 3]),
+            /* Slots released by a constrained job go to the unconstrained ones */
+            (&["ranks: pow2", ""], 10, &[4, 6]),
+            (&["ranks: square", "", ""], 15, &[4, 6, 5]),
+            (&["ranks_multiple_of: 4", "", "ranks: pow2"], 15, &[4, 7, 4]),
+            /* Only constrained jobs grow in turn with what was released */
+            (&["ranks: pow2", "ranks: pow2"], 12, &[8, 4]),
+            (&["ranks_multiple_of: 5", "ranks: square"], 13, &[5, 4]),
+        ];
+
+        for (constraints, slots, expected) in table {
+            let yaml: String = constraints
+                .iter()
+                .map(|c| format!("- map: Aslot\n  command: [a]\n  {}\n", c))
+                .collect();
+            assert_eq!(
+                shares(&yaml, slots).unwrap(),
+                expected,
+                "{:?} over {} slots",
+                constraints,
+                slots
+            );
+        }
+
+        let err = shares("- map: Aslot\n  command: [a]\n  ranks_multiple_of: 8\n", 5).unwrap_err();
+        assert!(err.to_string().contains("Cannot satisfy"), "{}", err);
+    }
+
+    #[test]
+    fn exclusive_all_jobs_shrink_to_their_share() {
+        /* 6 ranks would reserve the 2 other slots of n0.1, 4 fit in n0.0 */
+        let mut pmap = topology(1, 3, 4);
+        map(
+            &mut pmap,
+            "- map: Aslot\n  command: [a]\n  exclusive: numa\n- map: Aslot\n  command: [b]\n",
+        )
+        .unwrap();
+        assert_eq!(placed(&pmap, 0), ["n0.0"; 4]);
+        assert_eq!(
+            placed(&pmap, 1),
+            ["n0.1", "n0.1", "n0.1", "n0.1", "n0.2", "n0.2"]
+        );
+
+        /* 3 ranks would reserve the other slot of n1, 2 fill n0 */
+        let mut pmap = topology(3, 1, 2);
+        map(
+            &mut pmap,
+            "- map: Aslot\n  command: [a]\n  exclusive: node\n- map: Aslot\n  command: [b]\n",
+        )
+        .unwrap();
+        assert_eq!(placed(&pmap, 0), ["n0.0"; 2]);
+        assert_eq!(placed(&pmap, 1), ["n1.0", "n1.0", "n2.0"]);
+    }
+
+    #[test]
+    fn exclusive_all_job_larger_than_its_share() {
+        /* A single rank reserves the 8 slots of the node, the share is 4 */
+        let mut pmap = topology(1, 2, 4);
+        let err = map(
+            &mut pmap,
+            "- map: Aslot\n  command: [a]\n  exclusive: node\n- map: Aslot\n  command: [b]\n",
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("does not fit in its share of 4 slot(s): its smallest placement holds 8"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn search_after_greedy_failure() {
+        /* Compact fills n0.0, leaving no slot there for the cyclic 2numa */
+        let mut pmap = topology(1, 2, 2);
+        map(
+            &mut pmap,
+            "- map: 2slot\n  command: [a]\n- map: 2numa\n  command: [b]\n",
+        )
+        .unwrap();
+        assert_eq!(placed(&pmap, 0), ["n0.0", "n0.1"]);
+        assert_eq!(placed(&pmap, 1), ["n0.0", "n0.1"]);
+
+        /* Every placement of 3 slots spans both domains job1 has to avoid */
+        let mut pmap = topology(1, 2, 2);
+        let err = map(
+            &mut pmap,
+            "- name: sim\n  map: 3slot\n  command: [a]\n- map: 1slot\n  command: [b]\n  avoid: sim\n",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.starts_with("Greedy mapping failed: "), "{}", err);
+        assert!(err.contains("No placement satisfies all jobs"), "{}", err);
+        assert!(
+            err.contains("\n  - job1 failed 2 time(s), last: no room for 1slot"),
+            "{}",
+            err
+        );
+        assert_eq!(pmap.count_free_slots(), 4);
+    }
+
+    #[test]
+    fn features_restrict_nodes() {
+        /* n0 has two NUMA domains of 2 slots, n1 a single one of 4 slots */
+        let shapes = [
+            ("n0", 2, 2, vec![]),
+            ("n1", 1, 4, vec!["bigmem".to_string()]),
+        ];
+        let mut descs = Vec::new();
+        for (host, numas, slots, tags) in shapes {
+            for pu in 0..numas * slots {
+                descs.push(JobDesc {
+                    host: host.to_string(),
+                    rank: descs.len() as u32,
+                    numa: vec![pu / slots],
+                    pu: vec![vec![pu]],
+                    core: vec![vec![pu]],
+                    features: NodeFeatures {
+                        tags: tags.clone(),
+                        ..Default::default()
+                    },
+                });
             }
         }
 
-        Ok(ret)
+        let mut pmap = ProcMap::from_descs(&descs);
+        map(
+            &mut pmap,
+            "- map: 2slot\n  command: [a]\n- map: 3slot\n  command: [b]\n  features: [bigmem]\n",
+        )
+        .unwrap();
+        assert_eq!(placed(&pmap, 0), ["n0.0", "n0.0"]);
+        assert_eq!(placed(&pmap, 1), ["n1.0"; 3]);
+
+        let mut pmap = ProcMap::from_descs(&descs);
+        let err = map(
+            &mut pmap,
+            "- map: 1slot\n  command: [a]\n  features: [bigmem, gpu]\n",
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("No node provides features bigmem,gpu for job job0"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn colocate_with_shares_domains() {
+        let mut pmap = topology(2, 2, 4);
+        map(
+            &mut pmap,
+            "- name: sim\n  map: \"2\"\n  command: [a]\n  policy: scatter\n- map: \"2\"\n  command: [b]\n  colocate_with: sim\n",
+        )
+        .unwrap();
+        assert_eq!(placed(&pmap, 0), ["n0.0", "n1.0"]);
+        assert_eq!(placed(&pmap, 1), ["n0.0", "n0.0"]);
+    }
+
+    #[test]
+    fn avoid_denies_domains_both_ways() {
+        /* The avoiding job is mapped second */
+        let mut pmap = topology(1, 2, 4);
+        map(
+            &mut pmap,
+            "- name: sim\n  map: \"2\"\n  command: [a]\n- map: \"3\"\n  command: [b]\n  avoid: sim\n",
+        )
+        .unwrap();
+        assert_eq!(placed(&pmap, 0), ["n0.0", "n0.0"]);
+        assert_eq!(placed(&pmap, 1), ["n0.1", "n0.1", "n0.1"]);
+
+        /* The avoiding job is mapped first, the other one keeps away from it */
+        let mut pmap = topology(1, 2, 4);
+        map(
+            &mut pmap,
+            "- map: \"2\"\n  command: [a]\n  avoid: ana\n- name: ana\n  map: \"3\"\n  command: [b]\n",
+        )
+        .unwrap();
+        assert_eq!(placed(&pmap, 0), ["n0.0", "n0.0"]);
+        assert_eq!(placed(&pmap, 1), ["n0.1", "n0.1", "n0.1"]);
+    }
+
+    #[test]
+    fn colocate_with_a_job_mapped_later() {
+        let mut pmap = topology(1, 2, 4);
+        let err = map(
+            &mut pmap,
+            "- map: \"2\"\n  command: [a]\n  colocate_with: late\n- name: late\n  map: A\n  command: [b]\n",
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("late which is not mapped yet"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn conflicting_affinity_names_its_cause() {
+        /* Caught when loading the jobfile */
+        let err = JobList::parse(
+            "- name: sim\n  map: \"2\"\n  command: [a]\n- map: \"1\"\n  command: [b]\n  colocate_with: sim\n  avoid: sim\n",
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("cannot both colocate"), "{}", err);
+
+        let mut pmap = topology(1, 1, 4);
+        let err = map(
+            &mut pmap,
+            "- name: sim\n  map: \"2\"\n  command: [a]\n- name: mon\n  map: \"1\"\n  command: [b]\n  colocate_with: sim\n- map: \"1\"\n  command: [c]\n  colocate_with: sim\n  avoid: mon\n",
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("every NUMA domain of sim is excluded by anti-affinity"),
+            "{}",
+            err
+        );
+
+        let mut pmap = topology(2, 1, 4);
+        let err = map(
+            &mut pmap,
+            "- name: sim\n  map: \"2\"\n  command: [a]\n  nodes: n0\n- map: \"1\"\n  command: [b]\n  colocate_with: sim\n  nodes: n1\n",
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("excluded by its node selection"),
+            "{}",
+            err
+        );
     }
 }
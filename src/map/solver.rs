@@ -0,0 +1,411 @@
+use anyhow::anyhow;
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+
+use super::NumaFilter;
+use super::NumaRef;
+use super::ProcMap;
+use crate::joblist::JobEntry;
+use crate::JobList;
+
+/// Maximum number of partial placements explored before giving up
+const MAX_STATES: usize = 100_000;
+
+/// Maximum number of placements tried for a single job
+const MAX_CANDIDATES: usize = 64;
+
+/// Number of slots to take on each NUMA domain
+type Placement = Vec<(NumaRef, usize)>;
+
+/// Slot owners (job, reservation) per NUMA, used to skip already explored states
+type Signature = Vec<Vec<(Option<u32>, Option<u32>)>>;
+
+struct Solver<'a> {
+    jobs: &'a JobList,
+    fixed: Vec<&'a JobEntry>,
+    states: usize,
+    dead_ends: HashSet<(usize, Signature)>,
+    /// Failure count and last reason per job label
+    failures: BTreeMap<String, (usize, String)>,
+}
+
+/// Backtracking search over the placement of fixed jobs, "each" jobs have a
+/// single placement and "all" jobs are mapped greedily on each complete
+/// assignment of the fixed ones. On failure the error explains which jobs
+/// could not be placed.
+pub(super) fn solve(pmap: &mut ProcMap, jobs: &JobList) -> Result<()> {
+    /* Trials are not worth explaining, the caller reports the outcome */
+    let explain = pmap.explain;
+    pmap.explain = false;
+    let ret = search_placement(pmap, jobs);
+    pmap.explain = explain;
+    ret
+}
+
+fn search_placement(pmap: &mut ProcMap, jobs: &JobList) -> Result<()> {
+    if let Err(e) = pmap.map_each_jobs(jobs) {
+        return Err(anyhow!("\"each\" jobs cannot be mapped: {}", e));
+    }
+
+    let mut needed = 0;
+    for j in jobs.fixed_jobs() {
+        needed += j.fixed_size()? * j.cores_per_rank;
+    }
+
+    let free = pmap.count_free_slots();
+    if free < needed {
+        return Err(anyhow!(
+            "Fixed jobs need {} slots but only {} are left after \"each\" jobs",
+            needed,
+            free
+        ));
+    }
+
+    let mut solver = Solver {
+        jobs,
+        fixed: jobs.fixed_jobs().collect(),
+        states: 0,
+        dead_ends: HashSet::new(),
+        failures: BTreeMap::new(),
+    };
+
+    if solver.search(pmap, 0) {
+        return Ok(());
+    }
+
+    Err(anyhow!("{}", solver.report()))
+}
+
+impl Solver<'_> {
+    fn search(&mut self, pmap: &mut ProcMap, idx: usize) -> bool {
+        if self.states >= MAX_STATES {
+            return false;
+        }
+        self.states += 1;
+
+        let key = (idx, signature(pmap));
+        if self.dead_ends.contains(&key) {
+            return false;
+        }
+
+        if idx == self.fixed.len() {
+            let cp = pmap.checkpoint();
+            match pmap.map_all_jobs(self.jobs) {
+                Ok(()) => {
+                    pmap.commit(cp);
+                    return true;
+                }
+                Err(e) => {
+                    self.fail("\"all\" jobs".to_string(), e.to_string());
+                    pmap.rollback(cp);
+                    self.dead_ends.insert(key);
+                    return false;
+                }
+            }
+        }
+
+        let j = self.fixed[idx];
+
+        let candidates = match placements(pmap, self.jobs, j) {
+            Ok(c) => c,
+            Err(e) => {
+                self.fail(j.label(), e.to_string());
+                return false;
+            }
+        };
+
+        if candidates.is_empty() {
+            self.fail(
+                j.label(),
+                format!("no room for {} on the remaining slots", j.map),
+            );
+        }
+
+        for c in candidates {
+            let cp = pmap.checkpoint();
+
+            if let Err(e) = apply(pmap, &c, j) {
+                self.fail(j.label(), e.to_string());
+                pmap.rollback(cp);
+                continue;
+            }
+            pmap.reserve_exclusive(j);
+
+            if self.search(pmap, idx + 1) {
+                pmap.commit(cp);
+                return true;
+            }
+
+            pmap.rollback(cp);
+        }
+
+        self.dead_ends.insert(key);
+        false
+    }
+
+    fn fail(&mut self, label: String, reason: String) {
+        let entry = self.failures.entry(label).or_insert((0, String::new()));
+        entry.0 += 1;
+        entry.1 = reason;
+    }
+
+    fn report(&self) -> String {
+        let mut ret = if self.states >= MAX_STATES {
+            format!(
+                "Search stopped after exploring {} placements without finding a solution:",
+                self.states
+            )
+        } else {
+            format!(
+                "No placement satisfies all jobs ({} placements explored):",
+                self.states
+            )
+        };
+
+        let mut failures: Vec<_> = self.failures.iter().collect();
+        failures.sort_by_key(|f| std::cmp::Reverse(f.1 .0));
+
+        for (label, (count, reason)) in failures {
+            ret += &format!("\n  - {} failed {} time(s), last: {}", label, count, reason);
+        }
+
+        ret
+    }
+}
+
+fn signature(pmap: &ProcMap) -> Signature {
+    pmap.nodes
+        .values()
+        .flat_map(|node| node.numas.values())
+        /* Slot order matters, ranks over several cores need adjacent slots */
+        .map(|numa| numa.slots.iter().map(|s| (s.job, s.reserved)).collect())
+        .collect()
+}
+
+/// Free rank count of the NUMA domains a job may use, grouped by node
+fn free_by_node(pmap: &ProcMap, filter: &NumaFilter, job: &JobEntry) -> Vec<Vec<(NumaRef, usize)>> {
+    pmap.nodes
+        .iter()
+        .map(|(host, node)| {
+            node.numas
+                .iter()
+                .filter(|(id, _)| filter.accepts(host, **id))
+                .map(|(id, numa)| ((host.clone(), *id), numa.free_ranks(job).len()))
+                .collect::<Vec<_>>()
+        })
+        .filter(|n| !n.is_empty())
+        .collect()
+}
+
+/// Candidate placements of a fixed job, the greedy choice comes first
+fn placements(pmap: &ProcMap, jobs: &JobList, j: &JobEntry) -> Result<Vec<Placement>> {
+    let size = j.fixed_size()?;
+    let filter = pmap.numa_filter(jobs, j)?;
+    let nodes = free_by_node(pmap, &filter, j);
+
+    let mut ret: Vec<Placement> = Vec::new();
+
+    match j.loc_or_slot().as_str() {
+        "slot" => {
+            let domains: Vec<(NumaRef, usize)> = nodes.concat();
+
+            /* Compact placements starting on each domain, then every split */
+            for start in 0..domains.len() {
+                let mut rotated = domains[start..].to_vec();
+                rotated.extend_from_slice(&domains[..start]);
+                compositions(&rotated, size, ret.len() + 1, &mut Vec::new(), &mut ret);
+            }
+            compositions(
+                &domains,
+                size,
+                ret.len() + MAX_CANDIDATES,
+                &mut Vec::new(),
+                &mut ret,
+            );
+        }
+        "numa" => {
+            let domains: Vec<(NumaRef, usize)> = nodes.concat();
+            for counts in balanced(&domains.iter().map(|d| d.1).collect::<Vec<_>>(), size) {
+                ret.push(
+                    domains
+                        .iter()
+                        .zip(counts)
+                        .filter(|(_, c)| *c != 0)
+                        .map(|(d, c)| (d.0.clone(), c))
+                        .collect(),
+                );
+            }
+        }
+        "node" => {
+            let free: Vec<usize> = nodes.iter().map(|n| n.iter().map(|d| d.1).sum()).collect();
+            for counts in balanced(&free, size) {
+                let mut placement = Placement::new();
+                for (node, count) in nodes.iter().zip(counts) {
+                    /* Compact inside the node */
+                    let mut left = count;
+                    for (numa, free) in node.iter() {
+                        let take = left.min(*free);
+                        if take != 0 {
+                            placement.push((numa.clone(), take));
+                        }
+                        left -= take;
+                    }
+                }
+                ret.push(placement);
+            }
+        }
+        l => {
+            return Err(anyhow!("No such locality specifier {}", l));
+        }
+    }
+
+    let mut seen = HashSet::new();
+    ret.retain(|p| seen.insert(p.clone()));
+    ret.truncate(MAX_CANDIDATES);
+
+    Ok(ret)
+}
+
+/// Ways to split size over domains with a bounded free count, larger takes
+/// first, stops once out holds stop_at placements
+fn compositions(
+    domains: &[(NumaRef, usize)],
+    size: usize,
+    stop_at: usize,
+    current: &mut Placement,
+    out: &mut Vec<Placement>,
+) {
+    if stop_at <= out.len() {
+        return;
+    }
+
+    if size == 0 {
+        out.push(current.clone());
+        return;
+    }
+
+    let ((numa, free), rest) = match domains.split_first() {
+        Some(v) => v,
+        None => return,
+    };
+
+    let room: usize = rest.iter().map(|d| d.1).sum();
+
+    for take in (0..=size.min(*free)).rev() {
+        if room < size - take {
+            break;
+        }
+
+        if take != 0 {
+            current.push((numa.clone(), take));
+        }
+        compositions(rest, size - take, stop_at, current, out);
+        if take != 0 {
+            current.pop();
+        }
+    }
+}
+
+/// Splits of size where every domain gets size / n and size % n of them get
+/// one more, the choice of these domains is what the greedy mapper cannot revisit
+fn balanced(free: &[usize], size: usize) -> Vec<Vec<usize>> {
+    let n = free.len();
+
+    let quantum = match size.checked_div(n) {
+        Some(q) => q,
+        None => return Vec::new(),
+    };
+    let rest = size - (quantum * n);
+
+    if free.iter().any(|f| *f < quantum) {
+        return Vec::new();
+    }
+
+    let extra: Vec<usize> = (0..n).filter(|i| quantum < free[*i]).collect();
+    let mut ret = Vec::new();
+
+    for subset in combinations(&extra, rest) {
+        let mut counts = vec![quantum; n];
+        for i in subset {
+            counts[i] += 1;
+        }
+        ret.push(counts);
+    }
+
+    ret
+}
+
+/// Subsets of k elements in lexicographic order (bounded)
+fn combinations(items: &[usize], k: usize) -> Vec<Vec<usize>> {
+    let mut ret = Vec::new();
+    let mut current = Vec::new();
+
+    fn walk(items: &[usize], k: usize, current: &mut Vec<usize>, ret: &mut Vec<Vec<usize>>) {
+        if MAX_CANDIDATES <= ret.len() {
+            return;
+        }
+        if current.len() == k {
+            ret.push(current.clone());
+            return;
+        }
+        for (i, item) in items.iter().enumerate() {
+            if items.len() - i < k - current.len() {
+                break;
+            }
+            current.push(*item);
+            walk(&items[i + 1..], k, current, ret);
+            current.pop();
+        }
+    }
+
+    walk(items, k, &mut current, &mut ret);
+    ret
+}
+
+/// Give a job the first free ranks of each NUMA domain of a placement
+fn apply(pmap: &mut ProcMap, placement: &Placement, job: &JobEntry) -> Result<()> {
+    for ((host, id), count) in placement {
+        for _ in 0..*count {
+            let idx = match pmap.nodes.get(host).and_then(|n| n.numas.get(id)) {
+                Some(n) => n.free_ranks(job).first().copied(),
+                None => return Err(anyhow!("No such NUMA {} on {}", id, host)),
+            };
+
+            match idx {
+                Some(idx) => pmap.acquire_rank(&(host.clone(), *id, idx), job)?,
+                None => return Err(anyhow!("No free slot on numa {}", id)),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::JobDesc;
+
+    #[test]
+    fn signature_keeps_slot_order() {
+        let descs: Vec<JobDesc> = (0..3)
+            .map(|pu| JobDesc {
+                host: "n0".to_string(),
+                rank: pu as u32,
+                numa: vec![0],
+                pu: vec![vec![pu]],
+                core: vec![vec![pu]],
+                features: Default::default(),
+            })
+            .collect();
+
+        /* Same owners, only the first leaves two adjacent free slots */
+        let mut first = ProcMap::from_descs(&descs);
+        first.acquire_slot(&("n0".to_string(), 0, 0), 0).unwrap();
+        let mut second = ProcMap::from_descs(&descs);
+        second.acquire_slot(&("n0".to_string(), 0, 1), 0).unwrap();
+
+        assert_ne!(signature(&first), signature(&second));
+    }
+}
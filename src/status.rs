@@ -0,0 +1,300 @@
+use anyhow::anyhow;
+use anyhow::Result;
+use regex::Regex;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+
+use crate::exec::Plan;
+use crate::joblist::OnFailure;
+
+/// Status of the tasks by job and task
+pub(crate) type Statuses = HashMap<(String, i32), TaskStatus>;
+
+/// How a launcher task ended
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct TaskStatus {
+    /// Label of the job of the task, tasks run several jobs over time
+    #[serde(default)]
+    pub(crate) job: String,
+    pub(crate) task: i32,
+    /// Exit code, none when the task did not exit by itself
+    pub(crate) code: Option<i32>,
+    /// Signal which ended the task, if known
+    pub(crate) signal: Option<i32>,
+    pub(crate) reason: String,
+    /// Stopped by the exec wrapper once the timeout of its job expired
+    #[serde(default)]
+    pub(crate) timed_out: bool,
+}
+
+impl TaskStatus {
+    pub(crate) fn from_exit(task: i32, status: ExitStatus) -> TaskStatus {
+        match (status.code(), status.signal()) {
+            (Some(c), _) => TaskStatus {
+                job: String::new(),
+                task,
+                code: Some(c),
+                signal: None,
+                reason: format!("exit code {}", c),
+                timed_out: false,
+            },
+            (None, Some(s)) => TaskStatus {
+                job: String::new(),
+                task,
+                code: None,
+                signal: Some(s),
+                reason: format!("killed by signal {}", s),
+                timed_out: false,
+            },
+            (None, None) => TaskStatus {
+                job: String::new(),
+                task,
+                code: None,
+                signal: None,
+                reason: "unknown status".to_string(),
+                timed_out: false,
+            },
+        }
+    }
+
+    pub(crate) fn failed(&self) -> bool {
+        self.code != Some(0)
+    }
+
+    /// Statuses are looked up by job and task
+    pub(crate) fn key(&self) -> (String, i32) {
+        (self.job.clone(), self.task)
+    }
+
+    /// Status files of the exec wrapper are named after the job and task
+    pub(crate) fn save(&self, dir: &Path) -> Result<()> {
+        let file = std::fs::File::create(dir.join(format!("{}.{}.json", self.job, self.task)))?;
+        serde_json::to_writer(file, self)?;
+        Ok(())
+    }
+
+    /// Empty lmap-status directory in dir where the exec wrapper saves the
+    /// status of each task, statuses of a previous run are removed
+    pub(crate) fn reset_dir(dir: &Path) -> Result<PathBuf> {
+        let ret = dir.join("lmap-status");
+        if ret.exists() {
+            std::fs::remove_dir_all(&ret)?;
+        }
+        std::fs::create_dir_all(&ret)?;
+        Ok(ret)
+    }
+
+    pub(crate) fn load_dir(dir: &Path) -> Statuses {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(e) => e,
+            Err(_) => return HashMap::new(),
+        };
+
+        entries
+            .filter_map(|e| e.ok())
+            .filter_map(|e| std::fs::read_to_string(e.path()).ok())
+            .filter_map(|s| serde_json::from_str::<TaskStatus>(&s).ok())
+            .map(|s| (s.key(), s))
+            .collect()
+    }
+
+    /// Failures srun reports on stderr, such as
+    /// "srun: error: nid001: tasks 0-3,5: Exited with exit code 2"
+    pub(crate) fn parse_srun(line: &str) -> Vec<TaskStatus> {
+        let re = Regex::new(r"tasks? ([0-9,\-]+): (.*)$").unwrap();
+        let code_re = Regex::new(r"Exited with exit code ([0-9]+)").unwrap();
+
+        let captures = match re.captures(line) {
+            Some(c) if line.starts_with("srun: error:") => c,
+            _ => return Vec::new(),
+        };
+
+        let code = code_re
+            .captures(&captures[2])
+            .and_then(|c| c[1].parse::<i32>().ok());
+        let reason = match code {
+            Some(c) => format!("exit code {}", c),
+            None => captures[2].trim().to_string(),
+        };
+
+        let mut ret = Vec::new();
+
+        for range in captures[1].split(',') {
+            let (first, last) = range.split_once('-').unwrap_or((range, range));
+            if let (Ok(a), Ok(b)) = (first.parse::<i32>(), last.parse::<i32>()) {
+                for task in a..=b {
+                    ret.push(TaskStatus {
+                        job: String::new(),
+                        task,
+                        code,
+                        signal: None,
+                        reason: reason.clone(),
+                        timed_out: false,
+                    });
+                }
+            }
+        }
+
+        ret
+    }
+}
+
+/// Print the status of each job and of its failed ranks, errors when a rank
+/// of a job not ignoring failures failed; without the exec wrapper ranks srun
+/// did not complain about are fine. Stopped tells why lmap stopped the jobs,
+/// if it did
+pub(crate) fn report(
+    plan: &Plan,
+    statuses: &Statuses,
+    wrapped: bool,
+    srun: ExitStatus,
+    stopped: Option<&str>,
+) -> Result<()> {
+    let mut rows: Vec<[String; 5]> = vec![[
+        "Job".to_string(),
+        "Ranks".to_string(),
+        "Failed".to_string(),
+        "Timed out".to_string(),
+        "Status".to_string(),
+    ]];
+    let mut failures: Vec<String> = Vec::new();
+    let mut failed_jobs = 0;
+    let mut timed_out_jobs = 0;
+    let mut ignored_jobs = 0;
+
+    for job in plan.jobs() {
+        let ranks: Vec<_> = plan.ranks.iter().filter(|r| r.job == job).collect();
+        let mut reasons: Vec<String> = Vec::new();
+        let mut failed = 0;
+        let mut timed_out = 0;
+
+        for r in ranks.iter() {
+            let status = statuses.get(&(r.job.clone(), r.task));
+            let reason = match status {
+                Some(s) if s.timed_out => {
+                    timed_out += 1;
+                    s.reason.clone()
+                }
+                Some(s) if s.failed() => s.reason.clone(),
+                Some(_) => continue,
+                None if stopped.is_some() => "stopped".to_string(),
+                None if wrapped => "no status reported".to_string(),
+                None => continue,
+            };
+
+            if !status.is_some_and(|s| s.timed_out) {
+                failed += 1;
+            }
+            failures.push(format!(
+                "{} rank {} (task {} on {}): {}",
+                job, r.rank, r.task, r.host, reason
+            ));
+            if !reasons.contains(&reason) {
+                reasons.push(reason);
+            }
+        }
+
+        let ignored = ranks
+            .first()
+            .is_some_and(|r| r.on_failure == Some(OnFailure::Ignore));
+
+        if ignored && failed + timed_out != 0 {
+            ignored_jobs += 1;
+        } else {
+            if failed != 0 {
+                failed_jobs += 1;
+            }
+            if timed_out != 0 {
+                timed_out_jobs += 1;
+            }
+        }
+
+        rows.push([
+            job.to_string(),
+            ranks.len().to_string(),
+            failed.to_string(),
+            timed_out.to_string(),
+            match (reasons.is_empty(), ignored) {
+                (true, _) => "ok".to_string(),
+                (false, true) => format!("{} (ignored)", reasons.join(", ")),
+                (false, false) => reasons.join(", "),
+            },
+        ]);
+    }
+
+    let mut widths = [0; 5];
+    for row in rows.iter() {
+        for (w, cell) in widths.iter_mut().zip(row.iter()) {
+            *w = (*w).max(cell.len());
+        }
+    }
+
+    println!();
+    for row in rows.iter() {
+        println!(
+            "{}",
+            row.iter()
+                .zip(widths)
+                .map(|(cell, w)| format!("{:w$}", cell, w = w))
+                .collect::<Vec<_>>()
+                .join("  ")
+                .trim_end()
+        );
+    }
+
+    if !failures.is_empty() {
+        println!();
+        for f in failures.iter() {
+            println!("{}", f);
+        }
+    }
+
+    if let Some(why) = stopped {
+        return Err(anyhow!("Jobs {}", why));
+    }
+
+    match (failed_jobs, timed_out_jobs) {
+        (0, 0) => {}
+        (f, 0) => return Err(anyhow!("{} job(s) failed", f)),
+        (0, t) => return Err(anyhow!("{} job(s) timed out", t)),
+        (f, t) => return Err(anyhow!("{} job(s) failed, {} timed out", f, t)),
+    }
+
+    /* srun fails as well when only ignored jobs failed */
+    if !srun.success() && ignored_jobs == 0 {
+        return Err(anyhow!(
+            "srun failed ({}) without reporting a failed rank",
+            TaskStatus::from_exit(-1, srun).reason
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn srun_errors() {
+        let st = TaskStatus::parse_srun("srun: error: n0: tasks 0-3,5: Exited with exit code 2");
+        let tasks: Vec<i32> = st.iter().map(|s| s.task).collect();
+        assert_eq!(tasks, [0, 1, 2, 3, 5]);
+        assert!(st
+            .iter()
+            .all(|s| s.code == Some(2) && s.reason == "exit code 2"));
+
+        let st = TaskStatus::parse_srun("srun: error: n1: task 7: Killed");
+        assert_eq!(st.len(), 1);
+        assert_eq!((st[0].task, st[0].code), (7, None));
+        assert_eq!(st[0].reason, "Killed");
+        assert!(st[0].failed());
+
+        assert!(TaskStatus::parse_srun("srun: job 42 queued and waiting for resources").is_empty());
+        assert!(TaskStatus::parse_srun("task 1: Exited with exit code 2").is_empty());
+    }
+}
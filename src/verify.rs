@@ -0,0 +1,112 @@
+use anyhow::anyhow;
+use anyhow::Result;
+use hwlocality::cpu::binding::CpuBindingFlags;
+use hwlocality::memory::binding::MemoryBindingFlags;
+use hwlocality::memory::nodeset::NodeSet;
+use hwlocality::Topology;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::exec::{launcher_rank, Plan};
+
+/// Binding of a task as seen from inside it
+#[derive(Serialize, Deserialize, Debug)]
+struct Probe {
+    task: i32,
+    host: String,
+    pus: Vec<usize>,
+    numas: Vec<usize>,
+}
+
+/// Print the actual binding of the current task
+pub(crate) fn probe() -> Result<()> {
+    let topology = Topology::new()?;
+
+    let pus = topology
+        .cpu_binding(CpuBindingFlags::PROCESS)?
+        .iter_set()
+        .map(|v| usize::try_from(v).unwrap())
+        .collect();
+
+    let (nodes, _): (NodeSet, _) = topology.memory_binding(MemoryBindingFlags::PROCESS)?;
+    let numas = nodes
+        .iter_set()
+        .map(|v| usize::try_from(v).unwrap())
+        .collect();
+
+    println!(
+        "{}",
+        serde_json::to_string(&Probe {
+            task: launcher_rank().unwrap_or(-1),
+            host: crate::current_host()?,
+            pus,
+            numas,
+        })?
+    );
+
+    Ok(())
+}
+
+/// Compare the probes output with the plan, memory binding is only checked
+/// when ranks were launched through lmap exec
+pub(crate) fn check(plan: &Plan, output: &str, memory: bool) -> Result<()> {
+    let probes: HashMap<i32, Probe> = output
+        .lines()
+        .filter_map(|l| serde_json::from_str::<Probe>(l).ok())
+        .map(|p| (p.task, p))
+        .collect();
+
+    let mut mismatches = 0;
+
+    for r in plan.ranks.iter() {
+        let what = format!("{} rank {} (task {} on {})", r.job, r.rank, r.task, r.host);
+
+        let p = match probes.get(&r.task) {
+            Some(p) => p,
+            None => {
+                println!("MISSING {}: no probe output", what);
+                mismatches += 1;
+                continue;
+            }
+        };
+
+        if p.host != r.host {
+            println!("HOST    {}: ran on {}", what, p.host);
+            mismatches += 1;
+        }
+
+        if p.pus != r.pus {
+            println!(
+                "CPU     {}: planned PUs {:?}, bound to {:?}",
+                what, r.pus, p.pus
+            );
+            mismatches += 1;
+        }
+
+        if memory && p.numas != r.numas {
+            println!(
+                "MEMORY  {}: planned NUMA {:?}, bound to {:?}",
+                what, r.numas, p.numas
+            );
+            mismatches += 1;
+        }
+    }
+
+    println!(
+        "Verified {} rank(s){}, {} mismatch(es)",
+        plan.ranks.len(),
+        if memory {
+            ""
+        } else {
+            " (memory binding not checked, use -x)"
+        },
+        mismatches
+    );
+
+    if mismatches != 0 {
+        return Err(anyhow!("Ranks are not bound as planned"));
+    }
+
+    Ok(())
+}